@@ -0,0 +1,652 @@
+//! The `.cjb` binary encoding of a `vm::Program`.
+//!
+//! All integers are little-endian. A file starts with a header, followed by a table describing
+//! every block and then the instructions of each block in table order:
+//!
+//! ```text
+//! header:      magic "CJB\0" | version: u16 | block count: u32
+//! block table: label length: u16 | label: UTF-8 bytes | instruction count: u32
+//! instruction: opcode: u8 | operands
+//! ```
+//!
+//! Registers, locals and block references are encoded as `u32` indices, immediates as `u64`
+//! and comparisons as a single byte. The first block is the program's entry point. Host
+//! functions are referenced by their arity as a single byte followed by their name, encoded
+//! like a block label, and are looked up in a `HostRegistry` when decoding.
+
+use std::collections::HashSet;
+
+use crate::{
+    error::{DecodeError, DecodeErrorKind},
+    host::{HostFunction, HostRegistry},
+    vm::{self, Comparison, Instruction},
+};
+
+pub const MAGIC: [u8; 4] = *b"CJB\0";
+pub const VERSION: u16 = 1;
+
+mod opcode {
+    pub const LOAD_IMMEDIATE: u8 = 0x01;
+    pub const LOAD: u8 = 0x02;
+    pub const STORE: u8 = 0x03;
+    pub const SET_LOCAL: u8 = 0x04;
+    pub const GET_LOCAL: u8 = 0x05;
+    pub const INCREMENT: u8 = 0x06;
+    pub const ADD: u8 = 0x07;
+    pub const SUBTRACT: u8 = 0x08;
+    pub const MULTIPLY: u8 = 0x09;
+    pub const DIVIDE: u8 = 0x0a;
+    pub const MODULO: u8 = 0x0b;
+    pub const COMPARE: u8 = 0x0c;
+    pub const BREAKPOINT: u8 = 0x0d;
+    pub const EXIT: u8 = 0x0e;
+    pub const JUMP: u8 = 0x0f;
+    pub const JUMP_CONDITIONAL: u8 = 0x10;
+    pub const CALL: u8 = 0x11;
+    pub const RETURN: u8 = 0x12;
+    pub const PUSH: u8 = 0x13;
+    pub const POP: u8 = 0x14;
+    pub const DUPLICATE: u8 = 0x15;
+    pub const SWAP: u8 = 0x16;
+    pub const CALL_NATIVE: u8 = 0x17;
+    pub const ABORT: u8 = 0x18;
+    pub const EXIT_WITH_RESULT: u8 = 0x19;
+}
+
+const COMPARISONS: [Comparison; 10] = [
+    Comparison::Equal,
+    Comparison::NotEqual,
+    Comparison::LessThan,
+    Comparison::LessOrEqual,
+    Comparison::GreaterThan,
+    Comparison::GreaterOrEqual,
+    Comparison::SignedLessThan,
+    Comparison::SignedLessOrEqual,
+    Comparison::SignedGreaterThan,
+    Comparison::SignedGreaterOrEqual,
+];
+
+/// Returns true if `bytes` starts with the `.cjb` magic header
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+pub fn encode(program: &vm::Program) -> Vec<u8> {
+    let mut writer = Writer(vec![]);
+    writer.bytes(&MAGIC);
+    writer.u16(VERSION);
+    writer.index(program.blocks.len());
+
+    for block in &program.blocks {
+        writer.str(&block.label);
+        writer.index(block.instructions.len());
+    }
+
+    for block in &program.blocks {
+        for instruction in &block.instructions {
+            writer.instruction(program, instruction);
+        }
+    }
+
+    writer.0
+}
+
+pub fn decode(bytes: &[u8], host: &HostRegistry) -> Result<vm::Program, DecodeError> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        host,
+    };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(reader.error_at(0, DecodeErrorKind::BadMagic));
+    }
+    let version_offset = reader.offset;
+    match reader.u16()? {
+        VERSION => {}
+        version => {
+            let kind = DecodeErrorKind::UnsupportedVersion(version);
+            return Err(reader.error_at(version_offset, kind));
+        }
+    }
+
+    let block_count = reader.u32()?;
+    let mut program = vm::Program::default();
+    let mut labels = HashSet::new();
+    let mut blocks = vec![];
+
+    for _ in 0..block_count {
+        let label_offset = reader.offset;
+        let label = reader.str()?;
+
+        if !labels.insert(label) {
+            return Err(reader.error_at(label_offset, DecodeErrorKind::DuplicateLabel));
+        }
+        let instruction_count = reader.u32()?;
+        blocks.push((program.make_block(label), instruction_count));
+    }
+
+    let targets: Vec<_> = blocks.iter().map(|(block, _)| *block).collect();
+    for (block, instruction_count) in blocks {
+        for _ in 0..instruction_count {
+            let instruction = reader.instruction(&targets)?;
+            program.block_mut(block).instructions.push(instruction);
+        }
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(reader.error_at(reader.offset, DecodeErrorKind::TrailingBytes));
+    }
+    Ok(program)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn instruction(&mut self, program: &vm::Program, instruction: &Instruction) {
+        match instruction {
+            Instruction::LoadImmediate { value } => {
+                self.u8(opcode::LOAD_IMMEDIATE);
+                self.u64(value.0);
+            }
+            Instruction::Load { reg } => self.with_index(opcode::LOAD, reg.0),
+            Instruction::Store { reg } => self.with_index(opcode::STORE, reg.0),
+            Instruction::SetLocal { local } => self.with_index(opcode::SET_LOCAL, local.0),
+            Instruction::GetLocal { local } => self.with_index(opcode::GET_LOCAL, local.0),
+            Instruction::Increment => self.u8(opcode::INCREMENT),
+            Instruction::Add { rhs } => self.with_index(opcode::ADD, rhs.0),
+            Instruction::Subtract { rhs } => self.with_index(opcode::SUBTRACT, rhs.0),
+            Instruction::Multiply { rhs } => self.with_index(opcode::MULTIPLY, rhs.0),
+            Instruction::Divide { rhs } => self.with_index(opcode::DIVIDE, rhs.0),
+            Instruction::Modulo { rhs } => self.with_index(opcode::MODULO, rhs.0),
+            Instruction::Compare { cond, lhs } => {
+                self.u8(opcode::COMPARE);
+                self.u8(COMPARISONS.iter().position(|x| x == cond).unwrap() as u8);
+                self.index(lhs.0);
+            }
+            Instruction::Breakpoint => self.u8(opcode::BREAKPOINT),
+            Instruction::Exit { result: None } => self.u8(opcode::EXIT),
+            Instruction::Exit {
+                result: Some(result),
+            } => self.with_index(opcode::EXIT_WITH_RESULT, result.0),
+            Instruction::Abort => self.u8(opcode::ABORT),
+            Instruction::Jump { target } => {
+                self.u8(opcode::JUMP);
+                self.block(program, target);
+            }
+            Instruction::JumpConditional {
+                true_target,
+                false_target,
+            } => {
+                self.u8(opcode::JUMP_CONDITIONAL);
+                self.block(program, true_target);
+                self.block(program, false_target);
+            }
+            Instruction::Call { target } => {
+                self.u8(opcode::CALL);
+                self.block(program, target);
+            }
+            Instruction::Return => self.u8(opcode::RETURN),
+            Instruction::Push => self.u8(opcode::PUSH),
+            Instruction::Pop => self.u8(opcode::POP),
+            Instruction::Duplicate => self.u8(opcode::DUPLICATE),
+            Instruction::Swap => self.u8(opcode::SWAP),
+            Instruction::CallNative { function } => {
+                self.u8(opcode::CALL_NATIVE);
+                self.u8(function.func.arity() as u8);
+                self.str(&function.name);
+            }
+        }
+    }
+
+    fn with_index(&mut self, opcode: u8, index: usize) {
+        self.u8(opcode);
+        self.index(index);
+    }
+
+    fn block(&mut self, program: &vm::Program, target: &vm::BlockTarget) {
+        assert!(
+            program.contains(*target),
+            "jump target should belong to the program being encoded"
+        );
+        self.index(target.0);
+    }
+
+    fn index(&mut self, index: usize) {
+        self.u32(u32::try_from(index).expect("index doesn't fit in 32 bits"));
+    }
+
+    fn str(&mut self, value: &str) {
+        let len = u16::try_from(value.len()).expect("string is too long");
+        self.u16(len);
+        self.bytes(value.as_bytes());
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    host: &'a HostRegistry,
+}
+
+impl<'a> Reader<'a> {
+    fn instruction(&mut self, targets: &[vm::BlockTarget]) -> Result<Instruction, DecodeError> {
+        let opcode_offset = self.offset;
+        let instruction = match self.u8()? {
+            opcode::LOAD_IMMEDIATE => Instruction::LoadImmediate {
+                value: vm::Value(self.u64()?),
+            },
+            opcode::LOAD => Instruction::Load { reg: self.reg()? },
+            opcode::STORE => Instruction::Store { reg: self.reg()? },
+            opcode::SET_LOCAL => Instruction::SetLocal {
+                local: self.local()?,
+            },
+            opcode::GET_LOCAL => Instruction::GetLocal {
+                local: self.local()?,
+            },
+            opcode::INCREMENT => Instruction::Increment,
+            opcode::ADD => Instruction::Add { rhs: self.reg()? },
+            opcode::SUBTRACT => Instruction::Subtract { rhs: self.reg()? },
+            opcode::MULTIPLY => Instruction::Multiply { rhs: self.reg()? },
+            opcode::DIVIDE => Instruction::Divide { rhs: self.reg()? },
+            opcode::MODULO => Instruction::Modulo { rhs: self.reg()? },
+            opcode::COMPARE => Instruction::Compare {
+                cond: self.comparison()?,
+                lhs: self.reg()?,
+            },
+            opcode::BREAKPOINT => Instruction::Breakpoint,
+            opcode::EXIT => Instruction::Exit { result: None },
+            opcode::EXIT_WITH_RESULT => Instruction::Exit {
+                result: Some(self.reg()?),
+            },
+            opcode::ABORT => Instruction::Abort,
+            opcode::JUMP => Instruction::Jump {
+                target: self.block(targets)?,
+            },
+            opcode::JUMP_CONDITIONAL => Instruction::JumpConditional {
+                true_target: self.block(targets)?,
+                false_target: self.block(targets)?,
+            },
+            opcode::CALL => Instruction::Call {
+                target: self.block(targets)?,
+            },
+            opcode::RETURN => Instruction::Return,
+            opcode::PUSH => Instruction::Push,
+            opcode::POP => Instruction::Pop,
+            opcode::DUPLICATE => Instruction::Duplicate,
+            opcode::SWAP => Instruction::Swap,
+            opcode::CALL_NATIVE => Instruction::CallNative {
+                function: self.host_function()?,
+            },
+            op => return Err(self.error_at(opcode_offset, DecodeErrorKind::UnknownOpcode(op))),
+        };
+        Ok(instruction)
+    }
+
+    fn reg(&mut self) -> Result<vm::VMRegister, DecodeError> {
+        Ok(vm::VMRegister(self.u32()? as usize))
+    }
+
+    fn local(&mut self) -> Result<vm::VMLocal, DecodeError> {
+        Ok(vm::VMLocal(self.u32()? as usize))
+    }
+
+    fn comparison(&mut self) -> Result<Comparison, DecodeError> {
+        let offset = self.offset;
+        let cond = self.u8()?;
+        COMPARISONS
+            .get(cond as usize)
+            .copied()
+            .ok_or_else(|| self.error_at(offset, DecodeErrorKind::UnknownComparison(cond)))
+    }
+
+    fn block(&mut self, targets: &[vm::BlockTarget]) -> Result<vm::BlockTarget, DecodeError> {
+        let offset = self.offset;
+        let index = self.u32()?;
+        targets
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| self.error_at(offset, DecodeErrorKind::BlockOutOfRange(index)))
+    }
+
+    fn host_function(&mut self) -> Result<HostFunction, DecodeError> {
+        let offset = self.offset;
+        let arity = self.u8()?;
+        let name = self.str()?;
+
+        let function = self.host.get(name).ok_or_else(|| {
+            let kind = DecodeErrorKind::UnknownHostFunction(name.to_string());
+            self.error_at(offset, kind)
+        })?;
+        if function.func.arity() != arity as usize {
+            let name = name.to_string();
+            let kind = DecodeErrorKind::SignatureMismatch { name, arity };
+            return Err(self.error_at(offset, kind));
+        }
+        Ok(function)
+    }
+
+    fn str(&mut self) -> Result<&'a str, DecodeError> {
+        let offset = self.offset;
+        let len = self.u16()? as usize;
+        std::str::from_utf8(self.take(len)?)
+            .map_err(|_| self.error_at(offset, DecodeErrorKind::InvalidString))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| self.error_at(self.offset, DecodeErrorKind::UnexpectedEof))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn error_at(&self, offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError { kind, offset }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        host::Func,
+        vm::{BasicBlock, BlockTarget, Program, VMLocal, VMRegister, Value},
+    };
+
+    fn host() -> HostRegistry {
+        extern "C" fn zero() -> u64 {
+            0
+        }
+        extern "C" fn one(x: u64) -> u64 {
+            x
+        }
+        extern "C" fn two(x: u64, _: u64) -> u64 {
+            x
+        }
+        extern "C" fn three(x: u64, _: u64, _: u64) -> u64 {
+            x
+        }
+
+        let mut host = HostRegistry::default();
+        host.register("zero", Func::FnNoArgsWithReturnInt64(zero));
+        host.register("one", Func::FnSingleInt64WithReturnInt64(one));
+        host.register("two", Func::FnDoubleInt64WithReturnInt64(two));
+        host.register("three", Func::FnTripleInt64WithReturnInt64(three));
+        host
+    }
+
+    fn block(label: &str, instructions: Vec<Instruction>) -> BasicBlock {
+        BasicBlock {
+            label: label.to_string(),
+            instructions,
+        }
+    }
+
+    /// The start of a file holding `block_count` blocks
+    fn header(block_count: u32) -> Writer {
+        let mut writer = Writer(vec![]);
+        writer.bytes(&MAGIC);
+        writer.u16(VERSION);
+        writer.u32(block_count);
+        writer
+    }
+
+    /// A file holding a single block labelled `A`, whose one instruction is `instruction`
+    fn single_instruction(instruction: &[u8]) -> Vec<u8> {
+        let mut writer = header(1);
+        writer.str("A");
+        writer.u32(1);
+        writer.bytes(instruction);
+        writer.0
+    }
+
+    /// Where the instruction of a `single_instruction` file starts
+    const INSTRUCTION_OFFSET: usize = 17;
+
+    fn decode_error(bytes: &[u8]) -> (DecodeErrorKind, usize) {
+        let err = decode(bytes, &host()).unwrap_err();
+        (err.kind, err.offset)
+    }
+
+    #[test]
+    fn round_trips_every_instruction() {
+        let host = host();
+        let (entry, other) = (BlockTarget(0), BlockTarget(1));
+        let mut instructions = vec![
+            Instruction::LoadImmediate {
+                value: Value(u64::MAX),
+            },
+            Instruction::Load { reg: VMRegister(1) },
+            Instruction::Store { reg: VMRegister(2) },
+            Instruction::SetLocal { local: VMLocal(3) },
+            Instruction::GetLocal { local: VMLocal(0) },
+            Instruction::Increment,
+            Instruction::Add { rhs: VMRegister(4) },
+            Instruction::Subtract { rhs: VMRegister(5) },
+            Instruction::Multiply { rhs: VMRegister(6) },
+            Instruction::Divide { rhs: VMRegister(7) },
+            Instruction::Modulo { rhs: VMRegister(1) },
+            Instruction::Breakpoint,
+            Instruction::Call { target: other },
+            Instruction::Push,
+            Instruction::Pop,
+            Instruction::Duplicate,
+            Instruction::Swap,
+        ];
+        instructions.extend(COMPARISONS.map(|cond| Instruction::Compare {
+            cond,
+            lhs: VMRegister(2),
+        }));
+        instructions.extend(
+            ["zero", "one", "two", "three"].map(|name| Instruction::CallNative {
+                function: host.get(name).unwrap(),
+            }),
+        );
+        instructions.push(Instruction::JumpConditional {
+            true_target: other,
+            false_target: entry,
+        });
+
+        let program = Program {
+            blocks: vec![
+                block("ENTRY", instructions),
+                block("OTHER", vec![Instruction::Exit { result: None }]),
+                block(
+                    "RESULT",
+                    vec![Instruction::Exit {
+                        result: Some(VMRegister(3)),
+                    }],
+                ),
+                block("ABORT", vec![Instruction::Abort]),
+                block("BACK", vec![Instruction::Jump { target: entry }]),
+                block("RETURN", vec![Instruction::Return]),
+                block("EMPTY", vec![]),
+            ],
+        };
+        assert_eq!(decode(&encode(&program), &host), Ok(program));
+    }
+
+    #[test]
+    fn encodes_the_documented_layout() {
+        let program = Program {
+            blocks: vec![block(
+                "AB",
+                vec![
+                    Instruction::LoadImmediate { value: Value(258) },
+                    Instruction::Compare {
+                        cond: Comparison::SignedLessThan,
+                        lhs: VMRegister(1),
+                    },
+                    Instruction::Exit {
+                        result: Some(VMRegister(2)),
+                    },
+                ],
+            )],
+        };
+
+        #[rustfmt::skip]
+        let expected = [
+            b'C', b'J', b'B', 0, 1, 0, 1, 0, 0, 0,
+            2, 0, b'A', b'B', 3, 0, 0, 0,
+            0x01, 2, 1, 0, 0, 0, 0, 0, 0,
+            0x0c, 6, 1, 0, 0, 0,
+            0x19, 2, 0, 0, 0,
+        ];
+        assert_eq!(encode(&program), expected);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = encode(&Program::default());
+        bytes[0] = b'X';
+        assert_eq!(decode_error(&bytes), (DecodeErrorKind::BadMagic, 0));
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut bytes = encode(&Program::default());
+        bytes[4] = 2;
+        assert_eq!(
+            decode_error(&bytes),
+            (DecodeErrorKind::UnsupportedVersion(2), 4)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = encode(&Program::default());
+        assert_eq!(
+            decode_error(&bytes[..6]),
+            (DecodeErrorKind::UnexpectedEof, 6)
+        );
+        let bytes = single_instruction(&[opcode::LOAD_IMMEDIATE, 1, 2]);
+        assert_eq!(
+            decode_error(&bytes),
+            (DecodeErrorKind::UnexpectedEof, INSTRUCTION_OFFSET + 1)
+        );
+    }
+
+    #[test]
+    fn rejects_labels_that_arent_utf8() {
+        let mut writer = header(1);
+        writer.u16(1);
+        writer.u8(0xff);
+        writer.u32(0);
+        assert_eq!(
+            decode_error(&writer.0),
+            (DecodeErrorKind::InvalidString, 10)
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() {
+        let mut writer = header(2);
+        for _ in 0..2 {
+            writer.str("A");
+            writer.u32(0);
+        }
+        assert_eq!(
+            decode_error(&writer.0),
+            (DecodeErrorKind::DuplicateLabel, 17)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        assert_eq!(
+            decode_error(&single_instruction(&[0xff])),
+            (DecodeErrorKind::UnknownOpcode(0xff), INSTRUCTION_OFFSET)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_comparisons() {
+        let bytes = single_instruction(&[opcode::COMPARE, 10, 1, 0, 0, 0]);
+        assert_eq!(
+            decode_error(&bytes),
+            (
+                DecodeErrorKind::UnknownComparison(10),
+                INSTRUCTION_OFFSET + 1
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_blocks_out_of_range() {
+        let bytes = single_instruction(&[opcode::JUMP, 1, 0, 0, 0]);
+        assert_eq!(
+            decode_error(&bytes),
+            (DecodeErrorKind::BlockOutOfRange(1), INSTRUCTION_OFFSET + 1)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_host_functions() {
+        let bytes = single_instruction(&[opcode::CALL_NATIVE, 1, 4, 0, b'n', b'o', b'p', b'e']);
+        assert_eq!(
+            decode_error(&bytes),
+            (
+                DecodeErrorKind::UnknownHostFunction("nope".to_string()),
+                INSTRUCTION_OFFSET + 1
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_host_functions_with_another_signature() {
+        let bytes = single_instruction(&[opcode::CALL_NATIVE, 2, 3, 0, b'o', b'n', b'e']);
+        let kind = DecodeErrorKind::SignatureMismatch {
+            name: "one".to_string(),
+            arity: 2,
+        };
+        assert_eq!(decode_error(&bytes), (kind, INSTRUCTION_OFFSET + 1));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = single_instruction(&[opcode::RETURN]);
+        bytes.push(0);
+        assert_eq!(
+            decode_error(&bytes),
+            (DecodeErrorKind::TrailingBytes, INSTRUCTION_OFFSET + 1)
+        );
+    }
+}
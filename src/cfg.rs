@@ -0,0 +1,449 @@
+//! The control flow graph of a program, where blocks are identified by their index in
+//! `Program::blocks` and edges follow `Jump` and `JumpConditional`.
+//!
+//! Calls aren't edges, as control comes back to the calling block. Instead every block that is
+//! called from a reachable block is a root of the graph alongside the entry block, so a block
+//! is reachable if the entry block can get to it through any mix of jumps and calls.
+
+use crate::vm::{BlockTarget, Instruction, Program};
+
+/// A loop found from the back-edges that jump to its header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// The one way into the loop, which dominates every block in it
+    pub header: usize,
+    /// Jumps from within the loop back to its header, as `(from, header)`
+    pub back_edges: Vec<(usize, usize)>,
+    /// Every block in the loop, header included, in ascending order
+    pub blocks: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    successors: Vec<Vec<usize>>,
+    predecessors: Vec<Vec<usize>>,
+    reverse_postorder: Vec<usize>,
+    /// Each block's immediate dominator, where roots and unreachable blocks have none
+    dominators: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Self {
+        let index = |target: &BlockTarget| program.contains(*target).then_some(target.0);
+        let blocks = &program.blocks;
+
+        let mut successors = vec![vec![]; blocks.len()];
+        let mut callees = vec![vec![]; blocks.len()];
+        for (i, block) in blocks.iter().enumerate() {
+            // Anything after the first instruction that leaves the block can never run
+            for instruction in &block.instructions {
+                match instruction {
+                    Instruction::Jump { target } => successors[i].extend(index(target)),
+                    Instruction::JumpConditional {
+                        true_target,
+                        false_target,
+                    } => successors[i]
+                        .extend(index(true_target).into_iter().chain(index(false_target))),
+                    Instruction::Call { target } => callees[i].extend(index(target)),
+                    _ => {}
+                }
+                if leaves_block(instruction) {
+                    break;
+                }
+            }
+            successors[i].dedup();
+        }
+
+        let mut predecessors = vec![vec![]; blocks.len()];
+        for (i, successors) in successors.iter().enumerate() {
+            for &successor in successors {
+                predecessors[successor].push(i);
+            }
+        }
+
+        let (reverse_postorder, roots) = reverse_postorder(&successors, &callees);
+        let mut reachable = vec![false; blocks.len()];
+        for &block in &reverse_postorder {
+            reachable[block] = true;
+        }
+
+        let dominators = dominators(&reverse_postorder, &predecessors, &roots);
+
+        Self {
+            successors,
+            predecessors,
+            reverse_postorder,
+            dominators,
+            reachable,
+        }
+    }
+
+    /// The number of blocks in the program, reachable or not
+    pub fn len(&self) -> usize {
+        self.successors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.successors.is_empty()
+    }
+
+    /// The blocks `block` can jump to
+    pub fn successors(&self, block: usize) -> &[usize] {
+        &self.successors[block]
+    }
+
+    /// The blocks that can jump to `block`, whether or not they are reachable themselves
+    pub fn predecessors(&self, block: usize) -> &[usize] {
+        &self.predecessors[block]
+    }
+
+    /// Every reachable block, ordered so that each comes before its successors other than
+    /// along back-edges, or jumps into blocks already found from an earlier root. The entry
+    /// block is always first.
+    pub fn reverse_postorder(&self) -> &[usize] {
+        &self.reverse_postorder
+    }
+
+    /// The closest block other than `block` that every path to `block` goes through, if any
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        self.dominators[block]
+    }
+
+    /// Returns true if every path from a root to `block` goes through `dominator`, which
+    /// includes `block` dominating itself
+    pub fn dominates(&self, dominator: usize, block: usize) -> bool {
+        if !self.reachable[block] {
+            return false;
+        }
+
+        let mut current = Some(block);
+        while let Some(block) = current {
+            if block == dominator {
+                return true;
+            }
+            current = self.dominators[block];
+        }
+        false
+    }
+
+    /// Returns true if the entry block can get to `block`
+    pub fn is_reachable(&self, block: usize) -> bool {
+        self.reachable[block]
+    }
+
+    /// The blocks the entry block can't get to, in ascending order
+    pub fn unreachable(&self) -> Vec<usize> {
+        (0..self.len()).filter(|&x| !self.reachable[x]).collect()
+    }
+
+    /// The natural loops of the graph, one per header, in reverse postorder of their headers
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops = vec![];
+        for &header in &self.reverse_postorder {
+            let back_edges: Vec<(usize, usize)> = self.predecessors[header]
+                .iter()
+                .filter(|&&from| self.dominates(header, from))
+                .map(|&from| (from, header))
+                .collect();
+            if back_edges.is_empty() {
+                continue;
+            }
+
+            // The loop is every block that reaches a back-edge without going through the header
+            let mut blocks = vec![header];
+            let mut pending: Vec<usize> = back_edges.iter().map(|(from, _)| *from).collect();
+            while let Some(block) = pending.pop() {
+                if blocks.contains(&block) {
+                    continue;
+                }
+                blocks.push(block);
+                let predecessors = self.predecessors[block].iter();
+                pending.extend(predecessors.filter(|&&x| self.reachable[x]));
+            }
+            blocks.sort_unstable();
+
+            loops.push(Loop {
+                header,
+                back_edges,
+                blocks,
+            });
+        }
+        loops
+    }
+}
+
+/// Whether `instruction` always leaves its block, so that nothing after it can run
+pub(crate) fn leaves_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jump { .. }
+            | Instruction::JumpConditional { .. }
+            | Instruction::Exit { .. }
+            | Instruction::Abort
+            | Instruction::Return
+    )
+}
+
+/// The reachable blocks in reverse postorder, starting from the entry block and then from each
+/// block called by a block already visited, along with those roots in the order they were
+/// visited. Each root's blocks follow the ones found from earlier roots.
+fn reverse_postorder(
+    successors: &[Vec<usize>],
+    callees: &[Vec<usize>],
+) -> (Vec<usize>, Vec<usize>) {
+    let mut visited = vec![false; successors.len()];
+    let mut reverse_postorder = vec![];
+    let mut roots = vec![];
+    let mut pending_roots = match successors.is_empty() {
+        true => vec![],
+        false => vec![0],
+    };
+
+    let mut next_root = 0;
+    while next_root < pending_roots.len() {
+        let root = pending_roots[next_root];
+        next_root += 1;
+        if std::mem::replace(&mut visited[root], true) {
+            continue;
+        }
+        roots.push(root);
+        let mut postorder = vec![];
+
+        // Each entry is a block and how many of its successors have been visited so far
+        let mut stack = vec![(root, 0)];
+        while let Some((block, next)) = stack.last_mut() {
+            let block = *block;
+            match successors[block].get(*next) {
+                Some(&successor) => {
+                    *next += 1;
+                    if !std::mem::replace(&mut visited[successor], true) {
+                        stack.push((successor, 0));
+                    }
+                }
+                None => {
+                    stack.pop();
+                    postorder.push(block);
+                    pending_roots.extend(&callees[block]);
+                }
+            }
+        }
+        reverse_postorder.extend(postorder.into_iter().rev());
+    }
+    (reverse_postorder, roots)
+}
+
+/// Finds immediate dominators with the iterative algorithm from Cooper, Harvey and Kennedy's
+/// "A Simple, Fast Dominance Algorithm", treating every root as a successor of one virtual
+/// root that dominates them all
+fn dominators(
+    reverse_postorder: &[usize],
+    predecessors: &[Vec<usize>],
+    roots: &[usize],
+) -> Vec<Option<usize>> {
+    const VIRTUAL_ROOT: usize = usize::MAX;
+
+    // Positions in reverse postorder, where the virtual root comes before everything
+    let mut order = vec![None; predecessors.len()];
+    for (i, &block) in reverse_postorder.iter().enumerate() {
+        order[block] = Some(i + 1);
+    }
+    let order_of = |block: usize| match block {
+        VIRTUAL_ROOT => 0,
+        block => order[block].expect("only reachable blocks are ordered"),
+    };
+
+    let mut idom: Vec<Option<usize>> = vec![None; predecessors.len()];
+    for &root in roots {
+        idom[root] = Some(VIRTUAL_ROOT);
+    }
+
+    let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+        while a != b {
+            while order_of(a) > order_of(b) {
+                a = idom[a].expect("processed blocks have a dominator");
+            }
+            while order_of(b) > order_of(a) {
+                b = idom[b].expect("processed blocks have a dominator");
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in reverse_postorder {
+            let mut processed = predecessors[block]
+                .iter()
+                .copied()
+                .filter(|&x| idom[x].is_some());
+
+            let mut dominator = match roots.contains(&block) {
+                true => VIRTUAL_ROOT,
+                false => match processed.next() {
+                    Some(first) => first,
+                    None => continue,
+                },
+            };
+            for predecessor in processed {
+                dominator = intersect(&idom, predecessor, dominator);
+            }
+
+            if idom[block] != Some(dominator) {
+                idom[block] = Some(dominator);
+                changed = true;
+            }
+        }
+    }
+
+    idom.into_iter()
+        .map(|x| x.filter(|&x| x != VIRTUAL_ROOT))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn joins_are_dominated_by_their_branch() {
+        let cfg = Cfg::new(&testing::parse(
+            r#"
+ENTRY:
+  LOAD_INT32 1
+  JUMP_EITHER #LEFT #RIGHT
+LEFT:
+  JUMP #JOIN
+RIGHT:
+  JUMP #JOIN
+JOIN:
+  RET
+"#,
+        ));
+
+        assert_eq!(cfg.successors(0), [1, 2]);
+        assert_eq!(cfg.predecessors(3), [1, 2]);
+        assert_eq!(cfg.reverse_postorder()[0], 0);
+        assert_eq!(cfg.immediate_dominator(0), None);
+        assert_eq!(cfg.immediate_dominator(1), Some(0));
+        assert_eq!(cfg.immediate_dominator(2), Some(0));
+        assert_eq!(cfg.immediate_dominator(3), Some(0));
+        assert!(cfg.dominates(0, 3));
+        assert!(!cfg.dominates(1, 3));
+        assert!(cfg.loops().is_empty());
+    }
+
+    #[test]
+    fn finds_nested_loops() {
+        let program = testing::parse(
+            r#"
+ENTRY:
+  JUMP #OUTER
+OUTER:
+  LOAD_REG r1
+  JUMP_EITHER #INNER #END
+INNER:
+  LOAD_REG r2
+  JUMP_EITHER #INNER_BODY #LATCH
+INNER_BODY:
+  JUMP #INNER
+LATCH:
+  JUMP #OUTER
+END:
+  RET
+"#,
+        );
+        // Blocks are numbered in the order the parser first comes across their labels
+        let block = |label| {
+            program
+                .blocks
+                .iter()
+                .position(|x| x.label == label)
+                .unwrap()
+        };
+        let [outer, inner, inner_body, latch, end] =
+            ["OUTER", "INNER", "INNER_BODY", "LATCH", "END"].map(block);
+        let cfg = Cfg::new(&program);
+
+        let sorted = |mut blocks: Vec<usize>| {
+            blocks.sort_unstable();
+            blocks
+        };
+        assert_eq!(
+            cfg.loops(),
+            [
+                Loop {
+                    header: outer,
+                    back_edges: vec![(latch, outer)],
+                    blocks: sorted(vec![outer, inner, inner_body, latch]),
+                },
+                Loop {
+                    header: inner,
+                    back_edges: vec![(inner_body, inner)],
+                    blocks: sorted(vec![inner, inner_body]),
+                },
+            ]
+        );
+        assert_eq!(cfg.immediate_dominator(latch), Some(inner));
+        assert_eq!(cfg.immediate_dominator(end), Some(outer));
+    }
+
+    #[test]
+    fn called_blocks_are_reachable_roots() {
+        let cfg = Cfg::new(&testing::parse(
+            r#"
+ENTRY:
+  CALL #CALLEE
+  RET
+CALLEE:
+  RETURN
+"#,
+        ));
+
+        assert!(cfg.successors(0).is_empty());
+        assert!(cfg.predecessors(1).is_empty());
+        assert!(cfg.is_reachable(1));
+        assert_eq!(cfg.reverse_postorder(), [0, 1]);
+        assert_eq!(cfg.immediate_dominator(1), None);
+        assert!(!cfg.dominates(0, 1));
+        assert!(cfg.dominates(1, 1));
+    }
+
+    #[test]
+    fn blocks_after_a_return_are_unreachable() {
+        let cfg = Cfg::new(&testing::parse(
+            r#"
+ENTRY:
+  RET
+DEAD:
+  RET
+"#,
+        ));
+
+        assert!(!cfg.is_reachable(1));
+        assert_eq!(cfg.unreachable(), [1]);
+        assert_eq!(cfg.reverse_postorder(), [0]);
+        assert!(!cfg.dominates(0, 1));
+    }
+
+    #[test]
+    fn instructions_after_a_jump_add_no_successors() {
+        let cfg = Cfg::new(&testing::parse(
+            r#"
+ENTRY:
+  JUMP #TAKEN
+  JUMP #SKIPPED
+TAKEN:
+  RET
+SKIPPED:
+  RET
+"#,
+        ));
+
+        assert_eq!(cfg.successors(0), [1]);
+        assert!(cfg.predecessors(2).is_empty());
+        assert_eq!(cfg.unreachable(), [2]);
+    }
+}
@@ -0,0 +1,234 @@
+//! Cross-checks the interpreter against JIT compiled code, which should always agree
+
+use std::fmt::Display;
+
+use crate::{
+    error::{LinkError, Trap, TrapKind},
+    interpreter::Status,
+    jit::{Executable, ExitStatus, Jit, Options},
+    vm::{Program, VMLocal, VMRegister, Value, VM},
+};
+
+/// A register, local or operand stack slot of the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Register(VMRegister),
+    Local(VMLocal),
+    /// A value on the operand stack, counting up from the bottom
+    Stack(usize),
+    /// The number of values on the operand stack
+    StackDepth,
+}
+
+impl Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Slot::Register(reg) => write!(f, "r{}", reg.0),
+            Slot::Local(local) => write!(f, ".{}", local.0),
+            Slot::Stack(index) => write!(f, "s{index}"),
+            Slot::StackDepth => write!(f, "depth"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub slot: Slot,
+    pub interpreted: Value,
+    pub compiled: Value,
+}
+
+/// The outcome of running a program through both execution tiers
+#[derive(Debug)]
+pub struct Report {
+    /// How the interpreted run ended
+    pub status: Status,
+    /// How the compiled run ended, if compiled code was run. It isn't when the interpreter
+    /// doesn't finish or runs out of fuel, or traps on something compiled code leaves to the
+    /// verifier to rule out. Compiled code takes fuel per block rather than per instruction, so
+    /// it never runs out first.
+    pub compiled_status: Option<Result<ExitStatus, Trap>>,
+    pub mismatches: Vec<Mismatch>,
+    pub interpreted: VM,
+    pub compiled: VM,
+}
+
+impl Report {
+    /// Returns true if both tiers ran to completion with the same result, or trapped for the
+    /// same reason, and left the VM in the same state. Trap locations aren't compared, as they
+    /// shift when the optimizer removes instructions.
+    pub fn is_match(&self) -> bool {
+        let same_outcome = match (&self.status, &self.compiled_status) {
+            (Status::Exited(interpreted), Some(Ok(ExitStatus::Exited(compiled)))) => {
+                interpreted == compiled
+            }
+            (Status::Trapped(interpreted), Some(Err(compiled))) => {
+                interpreted.kind == compiled.kind
+            }
+            _ => false,
+        };
+        same_outcome && self.mismatches.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.status, &self.compiled_status) {
+            (Status::Running, _) => return write!(f, "interpreter didn't run to completion"),
+            (Status::OutOfFuel, _) => {
+                return write!(f, "interpreter ran out of fuel, compiled code was not run")
+            }
+            (_, Some(Ok(ExitStatus::OutOfFuel))) => {
+                return write!(f, "JIT ran out of fuel but the interpreter didn't")
+            }
+            (Status::Trapped(trap), None) => {
+                return write!(f, "interpreter trapped, compiled code was not run: {trap}")
+            }
+            (Status::Exited(interpreted), Some(Ok(ExitStatus::Exited(compiled))))
+                if interpreted != compiled =>
+            {
+                return write!(
+                    f,
+                    "interpreter exited with result {interpreted:?} but the JIT with {compiled:?}"
+                )
+            }
+            (Status::Exited(_), Some(Err(trap))) => {
+                return write!(f, "JIT trapped but the interpreter exited: {trap}")
+            }
+            (Status::Trapped(trap), Some(Ok(_))) => {
+                return write!(f, "interpreter trapped but the JIT exited: {trap}")
+            }
+            (Status::Trapped(interpreted), Some(Err(compiled)))
+                if interpreted.kind != compiled.kind =>
+            {
+                return write!(
+                    f,
+                    "interpreter trapped with `{interpreted}` but the JIT with `{compiled}`"
+                )
+            }
+            _ => {}
+        }
+
+        if self.mismatches.is_empty() {
+            let (registers, locals) = (self.compiled.registers.len(), self.compiled.locals.len());
+            let stack = self.compiled.stack().len();
+            let trapped = match &self.status {
+                Status::Trapped(trap) => format!(" both trapped with {} and", trap.kind),
+                _ => String::new(),
+            };
+            return write!(
+                f,
+                "interpreter and JIT{trapped} agree on all {registers} registers, {locals} locals and {stack} stack values"
+            );
+        }
+
+        writeln!(
+            f,
+            "interpreter and JIT disagree on {} slot(s):",
+            self.mismatches.len()
+        )?;
+        for Mismatch {
+            slot,
+            interpreted,
+            compiled,
+        } in &self.mismatches
+        {
+            let (interpreted, compiled) = (interpreted.0, compiled.0);
+            writeln!(
+                f,
+                "    {slot:>4}: interpreter = {interpreted} ({interpreted:#x}), jit = {compiled} ({compiled:#x})"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `program` from its entry block through both the interpreter and JIT compiled code,
+/// each on its own copy of `vm`, and compares every register, local and stack value once they
+/// exit or trap. The program is compiled with `options`, metered and profiling if `vm` is.
+/// Fails if the program can't be JIT compiled.
+pub fn cross_check(program: &Program, vm: &VM, options: Options) -> Result<Report, LinkError> {
+    cross_check_optimized(program, program, vm, options)
+}
+
+/// Like `cross_check`, but interprets `original` and compiles `optimized`, checking that the
+/// optimizer rewrote the program into an equivalent one as well as that the tiers agree
+pub fn cross_check_optimized(
+    original: &Program,
+    optimized: &Program,
+    vm: &VM,
+    options: Options,
+) -> Result<Report, LinkError> {
+    let mut interpreted = vm.clone();
+    interpreted.rewind();
+    let status = interpreted.run(original);
+
+    let mut compiled = vm.clone();
+    let compiled_status = match &status {
+        Status::Exited(_) => true,
+        Status::Trapped(trap) => is_checked_by_compiled_code(&trap.kind),
+        Status::Running | Status::OutOfFuel => false,
+    };
+    let options = Options {
+        fuel: vm.fuel().is_some(),
+        profile: vm.is_profiling(),
+        ..options
+    };
+    let compiled_status = match compiled_status {
+        true => Some(Executable::new(Jit::compile_with(optimized, options)?).run(&mut compiled)),
+        false => None,
+    };
+
+    let registers = interpreted.registers.iter().zip(&compiled.registers);
+    let registers = registers
+        .enumerate()
+        .map(|(i, x)| (Slot::Register(VMRegister(i)), x));
+
+    let locals = interpreted.locals.iter().zip(&compiled.locals);
+    let locals = locals
+        .enumerate()
+        .map(|(i, x)| (Slot::Local(VMLocal(i)), x));
+
+    let depth = interpreted.stack_depth.max(compiled.stack_depth);
+    let stack = interpreted.stack.iter().zip(&compiled.stack).take(depth);
+    let stack = stack.enumerate().map(|(i, x)| (Slot::Stack(i), x));
+
+    let depths = (
+        Value(interpreted.stack_depth as u64),
+        Value(compiled.stack_depth as u64),
+    );
+    let depths = std::iter::once((Slot::StackDepth, (&depths.0, &depths.1)));
+
+    let mismatches = registers
+        .chain(locals)
+        .chain(depths)
+        .chain(stack)
+        .filter(|(_, (interpreted, compiled))| interpreted != compiled)
+        .map(|(slot, (interpreted, compiled))| Mismatch {
+            slot,
+            interpreted: *interpreted,
+            compiled: *compiled,
+        })
+        .collect();
+
+    Ok(Report {
+        status,
+        compiled_status,
+        mismatches,
+        interpreted,
+        compiled,
+    })
+}
+
+/// Whether compiled code traps on `kind` itself, rather than relying on the verifier to reject
+/// programs that would, so that it is safe to run when the interpreter traps this way
+fn is_checked_by_compiled_code(kind: &TrapKind) -> bool {
+    matches!(
+        kind,
+        TrapKind::CallStackOverflow
+            | TrapKind::StackOverflow
+            | TrapKind::StackUnderflow
+            | TrapKind::DivisionByZero
+            | TrapKind::Abort
+    )
+}
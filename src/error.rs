@@ -0,0 +1,336 @@
+use std::fmt::Display;
+
+use crate::{
+    jit::Fault,
+    vm::{VMLocal, VMRegister},
+};
+
+/// A 1-based position in `.cj` source, covering `len` characters from `column`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    ExpectedBlockLabel,
+    UnknownMnemonic,
+    BadOperand { expected: &'static str },
+    OperandCount { expected: usize, found: usize },
+    UndeclaredBlock,
+    DuplicateLabel,
+    UnknownHostFunction,
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::ExpectedBlockLabel => write!(f, "expected block label"),
+            ParseErrorKind::UnknownMnemonic => write!(f, "unknown mnemonic"),
+            ParseErrorKind::BadOperand { expected } => {
+                write!(f, "bad operand, expected {expected}")
+            }
+            ParseErrorKind::OperandCount { expected, found } => {
+                write!(f, "expected {expected} operand(s) but found {found}")
+            }
+            ParseErrorKind::UndeclaredBlock => write!(f, "missing declaration for block"),
+            ParseErrorKind::DuplicateLabel => write!(f, "duplicate block label"),
+            ParseErrorKind::UnknownHostFunction => write!(f, "no host function with this name"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+    pub token: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (found `{}` on {})", self.kind, self.token, self.span)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    InvalidString,
+    DuplicateLabel,
+    UnknownOpcode(u8),
+    UnknownComparison(u8),
+    BlockOutOfRange(u32),
+    UnknownHostFunction(String),
+    SignatureMismatch { name: String, arity: u8 },
+    TrailingBytes,
+}
+
+impl Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeErrorKind::BadMagic => write!(f, "not a cheekyjit bytecode file"),
+            DecodeErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported bytecode version {version}")
+            }
+            DecodeErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+            DecodeErrorKind::InvalidString => {
+                write!(f, "block label or host function name is not valid UTF-8")
+            }
+            DecodeErrorKind::DuplicateLabel => write!(f, "duplicate block label"),
+            DecodeErrorKind::UnknownOpcode(op) => write!(f, "unknown opcode {op:#04x}"),
+            DecodeErrorKind::UnknownComparison(cond) => {
+                write!(f, "unknown comparison {cond:#04x}")
+            }
+            DecodeErrorKind::BlockOutOfRange(idx) => {
+                write!(f, "reference to missing block {idx}")
+            }
+            DecodeErrorKind::UnknownHostFunction(name) => {
+                write!(f, "no host function named `{name}`")
+            }
+            DecodeErrorKind::SignatureMismatch { name, arity } => {
+                write!(f, "host function `{name}` doesn't take {arity} argument(s)")
+            }
+            DecodeErrorKind::TrailingBytes => write!(f, "unexpected bytes after the last block"),
+        }
+    }
+}
+
+/// Describes why a `.cjb` file was rejected, at the byte offset of the offending item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    pub offset: usize,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at byte offset {:#x})", self.kind, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Why a program stopped before it could exit, which both the interpreter and compiled code
+/// report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    NoEntryBlock,
+    RegisterOutOfRange(VMRegister),
+    LocalOutOfRange(VMLocal),
+    CallStackOverflow,
+    StackOverflow,
+    StackUnderflow,
+    DivisionByZero,
+    /// The program ran `ABORT`
+    Abort,
+    /// A jump or call to a block the program doesn't have, which the verifier rules out
+    UnknownBlock,
+    /// Emulated compiled code did something the emulator can't carry out, leaving the VM
+    /// rewound
+    Fault(Fault),
+}
+
+impl Display for TrapKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrapKind::NoEntryBlock => write!(f, "program has no entry block"),
+            TrapKind::RegisterOutOfRange(reg) => {
+                write!(f, "register r{} is out of range", reg.0)
+            }
+            TrapKind::LocalOutOfRange(local) => {
+                write!(f, "local .{} is out of range", local.0)
+            }
+            TrapKind::CallStackOverflow => {
+                write!(f, "calls are nested too deeply")
+            }
+            TrapKind::StackOverflow => write!(f, "operand stack is full"),
+            TrapKind::StackUnderflow => {
+                write!(f, "operand stack has too few values")
+            }
+            TrapKind::DivisionByZero => write!(f, "division by zero"),
+            TrapKind::Abort => write!(f, "program aborted"),
+            TrapKind::UnknownBlock => write!(f, "target block isn't part of the program"),
+            TrapKind::Fault(fault) => write!(f, "emulated code faulted: {fault}"),
+        }
+    }
+}
+
+/// Identifies a bytecode instruction by its block label and index within that block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub block: String,
+    pub instruction: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub location: Option<Location>,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(Location { block, instruction }) => {
+                write!(f, "{} at {block}[{instruction}]", self.kind)
+            }
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    NoEntryBlock,
+    RegisterOutOfRange(VMRegister),
+    LocalOutOfRange(VMLocal),
+    /// A jump or call to a block the program doesn't have
+    UnknownBlock,
+    /// The block can run off its end, which the interpreter treats as exiting but compiled code
+    /// doesn't
+    Unterminated,
+}
+
+impl Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyErrorKind::NoEntryBlock => write!(f, "program has no entry block"),
+            VerifyErrorKind::RegisterOutOfRange(reg) => {
+                write!(f, "register r{} is out of range", reg.0)
+            }
+            VerifyErrorKind::LocalOutOfRange(local) => {
+                write!(f, "local .{} is out of range", local.0)
+            }
+            VerifyErrorKind::UnknownBlock => write!(f, "target block isn't part of the program"),
+            VerifyErrorKind::Unterminated => {
+                write!(f, "block doesn't end with a jump, RET, RETURN or ABORT")
+            }
+        }
+    }
+}
+
+/// Describes why a program can't safely run on a VM, at the offending instruction. Blocks that
+/// don't end in a terminator are reported at the index one past their last instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub kind: VerifyErrorKind,
+    pub location: Option<Location>,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(Location { block, instruction }) => {
+                write!(f, "{} at {block}[{instruction}]", self.kind)
+            }
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// An environment variable that configures how programs run, set to a value it can't take
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub variable: &'static str,
+    pub value: String,
+    /// What the variable can be set to
+    pub expected: &'static str,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unsupported {} `{}`, expected {}",
+            self.variable, self.value, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A jump that compiled code can't be linked with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// The target is further away than the jump's encoding can reach
+    OutOfRange {
+        instr_offset: usize,
+        target_offset: usize,
+    },
+    /// A jump was recorded at an instruction that isn't one
+    NotABranch { instr_offset: usize },
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::OutOfRange {
+                instr_offset,
+                target_offset,
+            } => write!(
+                f,
+                "jump at {instr_offset:#x} can't reach its target at {target_offset:#x}"
+            ),
+            LinkError::NotABranch { instr_offset } => {
+                write!(f, "instruction at {instr_offset:#x} isn't a jump")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileErrorKind {
+    BadHeader,
+    MissingField,
+    BadCount,
+    TrailingField,
+    DuplicateLabel,
+}
+
+impl Display for ProfileErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileErrorKind::BadHeader => write!(f, "not a cheekyjit profile"),
+            ProfileErrorKind::MissingField => {
+                write!(f, "expected a block label followed by three counts")
+            }
+            ProfileErrorKind::BadCount => write!(f, "count is not a number"),
+            ProfileErrorKind::TrailingField => write!(f, "unexpected field after the counts"),
+            ProfileErrorKind::DuplicateLabel => write!(f, "duplicate block label"),
+        }
+    }
+}
+
+/// Describes why a profile file was rejected, at the 1-based line of the offending entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileError {
+    pub kind: ProfileErrorKind,
+    pub line: usize,
+}
+
+impl Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (on line {})", self.kind, self.line)
+    }
+}
+
+impl std::error::Error for ProfileError {}
@@ -0,0 +1,100 @@
+//! Rust functions that programs can call by name with `CALL_NATIVE`.
+//!
+//! A host function takes its arguments from the VM registers `r1` onwards, one per parameter,
+//! and its result replaces the accumulator. Functions use the C calling convention so that JIT
+//! compiled code can call them directly.
+
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
+
+/// The most parameters a host function can have
+pub const MAX_ARITY: usize = 3;
+
+/// A pointer to a host function, tagged with its signature
+#[derive(Debug, Clone, Copy)]
+pub enum Func {
+    FnNoArgsWithReturnInt64(extern "C" fn() -> u64),
+    FnSingleInt64WithReturnInt64(extern "C" fn(u64) -> u64),
+    FnDoubleInt64WithReturnInt64(extern "C" fn(u64, u64) -> u64),
+    FnTripleInt64WithReturnInt64(extern "C" fn(u64, u64, u64) -> u64),
+}
+
+impl Func {
+    /// The number of parameters the function takes
+    pub fn arity(&self) -> usize {
+        match self {
+            Func::FnNoArgsWithReturnInt64(_) => 0,
+            Func::FnSingleInt64WithReturnInt64(_) => 1,
+            Func::FnDoubleInt64WithReturnInt64(_) => 2,
+            Func::FnTripleInt64WithReturnInt64(_) => 3,
+        }
+    }
+
+    /// The address compiled code branches to when calling the function
+    pub fn address(&self) -> u64 {
+        match self {
+            Func::FnNoArgsWithReturnInt64(func) => *func as *const () as u64,
+            Func::FnSingleInt64WithReturnInt64(func) => *func as *const () as u64,
+            Func::FnDoubleInt64WithReturnInt64(func) => *func as *const () as u64,
+            Func::FnTripleInt64WithReturnInt64(func) => *func as *const () as u64,
+        }
+    }
+
+    /// Calls the function with the first `arity()` of `args`
+    pub fn call(&self, args: [u64; MAX_ARITY]) -> u64 {
+        match self {
+            Func::FnNoArgsWithReturnInt64(func) => func(),
+            Func::FnSingleInt64WithReturnInt64(func) => func(args[0]),
+            Func::FnDoubleInt64WithReturnInt64(func) => func(args[0], args[1]),
+            Func::FnTripleInt64WithReturnInt64(func) => func(args[0], args[1], args[2]),
+        }
+    }
+}
+
+/// Functions are told apart by their signature and address, rather than by comparing the
+/// pointers themselves
+impl PartialEq for Func {
+    fn eq(&self, other: &Self) -> bool {
+        self.arity() == other.arity() && self.address() == other.address()
+    }
+}
+
+impl Eq for Func {}
+
+impl Hash for Func {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.arity().hash(state);
+        self.address().hash(state);
+    }
+}
+
+/// A host function looked up by name, as called by `vm::Instruction::CallNative`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HostFunction {
+    pub name: String,
+    pub func: Func,
+}
+
+/// The host functions available to programs, which embedders register by name before parsing
+/// or decoding a program
+#[derive(Debug, Default, Clone)]
+pub struct HostRegistry {
+    functions: HashMap<String, Func>,
+}
+
+impl HostRegistry {
+    /// Makes `func` callable as `name`, returning the function it replaced if there was one
+    pub fn register(&mut self, name: &str, func: Func) -> Option<Func> {
+        self.functions.insert(name.to_string(), func)
+    }
+
+    pub fn get(&self, name: &str) -> Option<HostFunction> {
+        let func = *self.functions.get(name)?;
+        Some(HostFunction {
+            name: name.to_string(),
+            func,
+        })
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{
+    error::{Location, Trap, TrapKind},
+    host::{Func, MAX_ARITY},
+    profile::{COUNTERS_PER_BLOCK, ENTRIES, NOT_TAKEN, TAKEN},
+    vm::{
+        BlockTarget, Comparison, Instruction, Program, VMLocal, VMRegister, Value, MAX_CALL_DEPTH,
+        VM,
+    },
+};
+
+/// The state a VM is left in after the interpreter hands control back to the caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The program ran `RET`, or ran off the end of a block, along with the value `RET` handed
+    /// back if it had an operand
+    Exited(Option<Value>),
+    /// The program can be resumed from the next instruction
+    Running,
+    /// The program can't make progress, and stepping again reports the same error
+    Trapped(Trap),
+    /// The VM ran out of fuel before the next instruction, which it resumes from once refuelled
+    OutOfFuel,
+}
+
+/// The next instruction the interpreter will execute
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProgramCounter {
+    pub(crate) block: BlockTarget,
+    pub(crate) instruction: usize,
+}
+
+/// A call the interpreter will return from, which owns the next window of locals
+#[derive(Debug, Clone)]
+pub(crate) struct Frame {
+    pub(crate) return_to: ProgramCounter,
+}
+
+enum Flow {
+    Next,
+    Jump(BlockTarget),
+    Call(BlockTarget),
+    Return,
+    Exit(Option<Value>),
+}
+
+impl VM {
+    /// Interprets `program` until it exits, traps or runs out of fuel
+    pub fn run(&mut self, program: &Program) -> Status {
+        loop {
+            match self.step(program) {
+                Status::Running => continue,
+                status => return status,
+            }
+        }
+    }
+
+    /// Interprets at most `steps` instructions of `program`, returning `Status::Running` if the
+    /// program is yet to finish
+    pub fn run_for(&mut self, program: &Program, steps: usize) -> Status {
+        for _ in 0..steps {
+            match self.step(program) {
+                Status::Running => continue,
+                status => return status,
+            }
+        }
+        Status::Running
+    }
+
+    /// Interprets a single instruction of `program`, starting from its entry block if the VM
+    /// isn't part way through it. Once the program exits, the next step starts it over again.
+    pub fn step(&mut self, program: &Program) -> Status {
+        let pc = match &mut self.pc {
+            Some(pc) => pc,
+            None if program.blocks.is_empty() => {
+                return Status::Trapped(Trap {
+                    kind: TrapKind::NoEntryBlock,
+                    location: None,
+                })
+            }
+            None => self.pc.insert(ProgramCounter {
+                block: BlockTarget(0),
+                instruction: 0,
+            }),
+        };
+
+        let block = program.block(pc.block);
+        let Some(instruction) = block.instructions.get(pc.instruction) else {
+            self.rewind();
+            return Status::Exited(None);
+        };
+
+        if !self.consume_fuel() {
+            return Status::OutOfFuel;
+        }
+        if self.is_profiling() {
+            count(self, instruction);
+        }
+        let flow = execute(self, instruction);
+        let Some(pc) = &mut self.pc else {
+            unreachable!("instructions can't reset the program counter")
+        };
+
+        match flow {
+            Ok(Flow::Jump(block) | Flow::Call(block)) if !program.contains(block) => {
+                let kind = TrapKind::UnknownBlock;
+                let location = Some(pc.location(program));
+                return Status::Trapped(Trap { kind, location });
+            }
+            Ok(Flow::Next) => pc.instruction += 1,
+            Ok(Flow::Jump(block)) => {
+                pc.block = block;
+                pc.instruction = 0;
+            }
+            Ok(Flow::Call(_)) if self.frames.len() + 1 >= MAX_CALL_DEPTH => {
+                let kind = TrapKind::CallStackOverflow;
+                let location = Some(pc.location(program));
+                return Status::Trapped(Trap { kind, location });
+            }
+            Ok(Flow::Call(block)) => {
+                let return_to = ProgramCounter {
+                    block: std::mem::replace(&mut pc.block, block),
+                    instruction: pc.instruction + 1,
+                };
+                pc.instruction = 0;
+                self.frames.push(Frame { return_to });
+            }
+            Ok(Flow::Return) => match self.frames.pop() {
+                Some(frame) => *pc = frame.return_to,
+                None => {
+                    self.rewind();
+                    return Status::Exited(None);
+                }
+            },
+            Ok(Flow::Exit(result)) => {
+                self.rewind();
+                return Status::Exited(result);
+            }
+            Err(kind) => {
+                let location = Some(pc.location(program));
+                return Status::Trapped(Trap { kind, location });
+            }
+        }
+        Status::Running
+    }
+
+    /// The instruction of `program` the interpreter will execute next, if it is part way
+    /// through it
+    pub fn location(&self, program: &Program) -> Option<Location> {
+        self.pc.map(|pc| pc.location(program))
+    }
+
+    /// Abandons the program being interpreted, so the next step starts from its entry block
+    pub fn rewind(&mut self) {
+        self.pc = None;
+        self.frames.clear();
+    }
+}
+
+impl ProgramCounter {
+    pub(crate) fn location(&self, program: &Program) -> Location {
+        Location {
+            block: program.block(self.block).label.clone(),
+            instruction: self.instruction,
+        }
+    }
+}
+
+/// Counts the VM entering the block it is in, if it is at the start of it, and which way
+/// `instruction` goes, if it is a conditional jump
+fn count(vm: &mut VM, instruction: &Instruction) {
+    let taken = vm.accum_reg().0 != 0;
+    let is_branch = matches!(instruction, Instruction::JumpConditional { .. });
+    let Some(pc) = vm.pc.filter(|pc| pc.instruction == 0 || is_branch) else {
+        return;
+    };
+    let start = pc.block.0 * COUNTERS_PER_BLOCK;
+    let Some(counters) = vm.counters.get_mut(start..start + COUNTERS_PER_BLOCK) else {
+        return;
+    };
+
+    if pc.instruction == 0 {
+        counters[ENTRIES] += 1;
+    }
+    if is_branch {
+        counters[if taken { TAKEN } else { NOT_TAKEN }] += 1;
+    }
+}
+
+fn execute(vm: &mut VM, instruction: &Instruction) -> Result<Flow, TrapKind> {
+    match instruction {
+        Instruction::LoadImmediate { value } => *vm.accum_reg_mut() = *value,
+        Instruction::Load { reg } => *vm.accum_reg_mut() = get_reg(vm, reg)?,
+        Instruction::Store { reg } => *get_reg_mut(vm, reg)? = *vm.accum_reg(),
+        Instruction::SetLocal { local } => *get_local_mut(vm, local)? = *vm.accum_reg(),
+        Instruction::GetLocal { local } => *vm.accum_reg_mut() = get_local(vm, local)?,
+        Instruction::Increment => vm.accum_reg_mut().0 = vm.accum_reg().0.wrapping_add(1),
+        Instruction::Add { rhs } => vm.accum_reg_mut().0 = binary_op(vm, rhs, u64::wrapping_add)?,
+        Instruction::Subtract { rhs } => {
+            vm.accum_reg_mut().0 = binary_op(vm, rhs, u64::wrapping_sub)?
+        }
+        Instruction::Multiply { rhs } => {
+            vm.accum_reg_mut().0 = binary_op(vm, rhs, u64::wrapping_mul)?
+        }
+        Instruction::Divide { rhs } => vm.accum_reg_mut().0 = divide(vm, rhs, u64::checked_div)?,
+        Instruction::Modulo { rhs } => vm.accum_reg_mut().0 = divide(vm, rhs, u64::checked_rem)?,
+        Instruction::Compare { cond, lhs } => vm.accum_reg_mut().0 = compare(vm, *cond, lhs)?,
+        Instruction::Breakpoint => breakpoint(),
+        Instruction::Exit { result } => {
+            let result = result.map(|reg| get_reg(vm, &reg)).transpose()?;
+            return Ok(Flow::Exit(result));
+        }
+        Instruction::Abort => return Err(TrapKind::Abort),
+        Instruction::Jump { target } => return Ok(Flow::Jump(*target)),
+        Instruction::Call { target } => return Ok(Flow::Call(*target)),
+        Instruction::Return => return Ok(Flow::Return),
+        Instruction::Push => push(vm, *vm.accum_reg())?,
+        Instruction::Pop => *vm.accum_reg_mut() = pop(vm)?,
+        Instruction::Duplicate => {
+            let top = stack_top(vm, 1)?[0];
+            push(vm, top)?
+        }
+        Instruction::Swap => stack_top(vm, 2)?.swap(0, 1),
+        Instruction::CallNative { function } => {
+            vm.accum_reg_mut().0 = call_native(vm, function.func)?
+        }
+        Instruction::JumpConditional {
+            true_target: t,
+            false_target: f,
+        } => {
+            let target = if vm.accum_reg().0 != 0 { t } else { f };
+            return Ok(Flow::Jump(*target));
+        }
+    }
+    Ok(Flow::Next)
+}
+
+fn get_reg(vm: &VM, reg: &VMRegister) -> Result<Value, TrapKind> {
+    let err = TrapKind::RegisterOutOfRange(*reg);
+    vm.registers.get(reg.0).ok_or(err).copied()
+}
+
+fn get_reg_mut<'a>(vm: &'a mut VM, reg: &VMRegister) -> Result<&'a mut Value, TrapKind> {
+    let err = TrapKind::RegisterOutOfRange(*reg);
+    vm.registers.get_mut(reg.0).ok_or(err)
+}
+
+/// Finds `local` within the window of locals belonging to the current frame
+fn local_index(vm: &VM, local: &VMLocal) -> Result<usize, TrapKind> {
+    match local.0 < vm.frame_size {
+        true => Ok(vm.frames.len() * vm.frame_size + local.0),
+        false => Err(TrapKind::LocalOutOfRange(*local)),
+    }
+}
+
+fn get_local(vm: &VM, local: &VMLocal) -> Result<Value, TrapKind> {
+    let err = TrapKind::LocalOutOfRange(*local);
+    vm.locals.get(local_index(vm, local)?).ok_or(err).copied()
+}
+
+fn get_local_mut<'a>(vm: &'a mut VM, local: &VMLocal) -> Result<&'a mut Value, TrapKind> {
+    let err = TrapKind::LocalOutOfRange(*local);
+    let index = local_index(vm, local)?;
+    vm.locals.get_mut(index).ok_or(err)
+}
+
+fn push(vm: &mut VM, value: Value) -> Result<(), TrapKind> {
+    let slot = vm.stack.get_mut(vm.stack_depth);
+    *slot.ok_or(TrapKind::StackOverflow)? = value;
+    vm.stack_depth += 1;
+    Ok(())
+}
+
+fn pop(vm: &mut VM) -> Result<Value, TrapKind> {
+    let value = stack_top(vm, 1)?[0];
+    vm.stack_depth -= 1;
+    Ok(value)
+}
+
+/// The top `count` values of the operand stack, leaving the stack untouched if it holds fewer
+fn stack_top(vm: &mut VM, count: usize) -> Result<&mut [Value], TrapKind> {
+    let start = vm.stack_depth.checked_sub(count);
+    let start = start.ok_or(TrapKind::StackUnderflow)?;
+    Ok(&mut vm.stack[start..vm.stack_depth])
+}
+
+fn call_native(vm: &VM, func: Func) -> Result<u64, TrapKind> {
+    let mut args = [0; MAX_ARITY];
+    for (i, arg) in args.iter_mut().enumerate().take(func.arity()) {
+        *arg = get_reg(vm, &VMRegister(i + 1))?.0;
+    }
+    Ok(func.call(args))
+}
+
+fn binary_op(vm: &VM, rhs: &VMRegister, op: impl FnOnce(u64, u64) -> u64) -> Result<u64, TrapKind> {
+    Ok(op(vm.accum_reg().0, get_reg(vm, rhs)?.0))
+}
+
+/// Like `binary_op`, but trapping when `op` finds the divisor to be zero
+fn divide(
+    vm: &VM,
+    rhs: &VMRegister,
+    op: impl FnOnce(u64, u64) -> Option<u64>,
+) -> Result<u64, TrapKind> {
+    op(vm.accum_reg().0, get_reg(vm, rhs)?.0).ok_or(TrapKind::DivisionByZero)
+}
+
+fn compare(vm: &VM, cond: Comparison, lhs: &VMRegister) -> Result<u64, TrapKind> {
+    let holds = cond.evaluate(get_reg(vm, lhs)?.0, vm.accum_reg().0);
+    Ok(holds as u64)
+}
+
+fn breakpoint() {
+    // Safety: traps into an attached debugger, no registers or memory are touched
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!("brk 0")
+    }
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::asm!("int3")
+    }
+}
@@ -1,23 +1,106 @@
-use crate::{vm::BlockTarget, vm::VMLocal, vm::VMRegister};
+use crate::{
+    error::LinkError,
+    host::Func,
+    vm::{BlockTarget, Comparison, VMLocal, VMRegister, MAX_CALL_DEPTH, MAX_STACK_DEPTH, VM},
+};
+
+use super::backend::{trap, Backend, BinaryOp};
 
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg {
     GPR0 = 4, // x4
     GPR1 = 5, // x5
+    GPR2 = 6, // x6
 
-    VmStructBase = 0,     // x0
+    VmStructBase = 0,      // x0
     RegisterArrayBase = 1, // x1
     LocalsArrayBase = 2,   // x2
+    StackArrayBase = 3,    // x3
+
+    EntryFrame = 19, // x19, the stack pointer once the prologue has run
+    StackDepth = 20, // x20
+
+    // Callee-saved registers that hold VM registers r0..r7 when they are pinned
+    Pinned0 = 21, // x21
+    Pinned1 = 22, // x22
+    Pinned2 = 23, // x23
+    Pinned3 = 24, // x24
+    Pinned4 = 25, // x25
+    Pinned5 = 26, // x26
+    Pinned6 = 27, // x27
+    Pinned7 = 28, // x28
 
+    FP = 29,
     RET = 30,
     SP = 31,
 }
 
-pub enum Func {
-    FnSingleInt64WithReturnInt64(fn(u64) -> u64, u64),
+/// Condition codes tested against the NZCV flags by `CSET` and `B.cond`
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    EQ = 0b0000, // equal
+    NE = 0b0001, // not equal
+    HS = 0b0010, // unsigned higher or same
+    LO = 0b0011, // unsigned lower
+    HI = 0b1000, // unsigned higher
+    LS = 0b1001, // unsigned lower or same
+    GE = 0b1010, // signed greater than or equal
+    LT = 0b1011, // signed less than
+    GT = 0b1100, // signed greater than
+    LE = 0b1101, // signed less than or equal
+}
+
+impl Cond {
+    /// The condition that holds exactly when this one doesn't
+    fn invert(self) -> Self {
+        match self {
+            Cond::EQ => Cond::NE,
+            Cond::NE => Cond::EQ,
+            Cond::HS => Cond::LO,
+            Cond::LO => Cond::HS,
+            Cond::HI => Cond::LS,
+            Cond::LS => Cond::HI,
+            Cond::GE => Cond::LT,
+            Cond::LT => Cond::GE,
+            Cond::GT => Cond::LE,
+            Cond::LE => Cond::GT,
+        }
+    }
+}
+
+impl From<Comparison> for Cond {
+    fn from(cond: Comparison) -> Self {
+        match cond {
+            Comparison::Equal => Cond::EQ,
+            Comparison::NotEqual => Cond::NE,
+            Comparison::LessThan => Cond::LO,
+            Comparison::LessOrEqual => Cond::LS,
+            Comparison::GreaterThan => Cond::HI,
+            Comparison::GreaterOrEqual => Cond::HS,
+            Comparison::SignedLessThan => Cond::LT,
+            Comparison::SignedLessOrEqual => Cond::LE,
+            Comparison::SignedGreaterThan => Cond::GT,
+            Comparison::SignedGreaterOrEqual => Cond::GE,
+        }
+    }
 }
 
+/// Where each pinned VM register lives, indexed by register number
+const PINNED_REGISTERS: [Reg; 8] = [
+    Reg::Pinned0,
+    Reg::Pinned1,
+    Reg::Pinned2,
+    Reg::Pinned3,
+    Reg::Pinned4,
+    Reg::Pinned5,
+    Reg::Pinned6,
+    Reg::Pinned7,
+];
+
 #[derive(Debug, Clone, Copy)]
 enum Operand {
     Reg(Reg),
@@ -25,125 +108,536 @@ enum Operand {
     Mem64BaseAndOffset(Reg, usize),
 }
 
+/// How far `B.cond` can reach in either direction
+const COND_BRANCH_RANGE: usize = 1 << 20;
+
+/// An upper bound on the code emitted between two checks for whether a veneer island is due
+const VENEER_ISLAND_MARGIN: usize = 4096;
+
 #[derive(Default)]
 pub struct Assembler {
     output: Vec<u8>,
+    host_calls: Vec<Func>,
+    /// Conditional branches that aren't yet linked, as they may need a veneer to reach their
+    /// target
+    pending_branches: Vec<(usize, BlockTarget)>,
+    /// Jumps and calls to blocks, which are linked once every block has been emitted
+    jumps: Vec<(usize, BlockTarget)>,
+    /// How many VM registers, counting up from r0, live in `PINNED_REGISTERS` rather than
+    /// memory
+    pinned: usize,
+    known: Known,
+    trap_handler: Option<usize>,
+    trap_site: usize,
 }
 
-impl Assembler {
-    pub fn load_immediate64(&mut self, dst: Reg, imm: u64) {
+/// A VM register or local, wherever it currently lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Register(VMRegister),
+    Local(VMLocal),
+}
+
+/// What the instructions emitted since the start of the block are known to have left behind,
+/// so that the peephole optimizer can skip reloading a slot that was just stored or loaded,
+/// and branch on the flags `CSET` was set from rather than comparing its result with zero
+#[derive(Debug, Default, Clone)]
+struct Known {
+    /// Registers holding the same value as a slot
+    copies: Vec<(Reg, Slot)>,
+    /// A register that `CSET` set to whether a condition holds, where the flags it tested are
+    /// still live
+    flags: Option<(Reg, Cond)>,
+}
+
+impl Known {
+    fn holds(&self, reg: Reg, slot: Slot) -> bool {
+        self.copies.contains(&(reg, slot))
+    }
+
+    /// Stores leave registers and flags alone, but overwrite every other copy of the slot
+    fn after_store(mut self, src: Reg, slot: Slot) -> Self {
+        self.copies.retain(|(_, x)| *x != slot);
+        self.copies.push((src, slot));
+        self
+    }
+
+    /// Loads overwrite whatever `dst` held before
+    fn after_load(mut self, dst: Reg, slot: Slot) -> Self {
+        self.copies.retain(|(reg, _)| *reg != dst);
+        self.copies.push((dst, slot));
+        self.flags = self.flags.filter(|(reg, _)| *reg != dst);
+        self
+    }
+}
+
+impl Backend for Assembler {
+    fn load_immediate64(&mut self, dst: Reg, imm: u64) {
         self.mov(Operand::Reg(dst), Operand::Imm64(imm));
     }
 
-    pub fn store_vm_register(&mut self, dst: VMRegister, src: Reg) {
-        self.mov(
-            Operand::Mem64BaseAndOffset(Reg::RegisterArrayBase, dst.0),
-            Operand::Reg(src),
-        );
+    fn store_vm_register(&mut self, dst: VMRegister, src: Reg) {
+        let known = std::mem::take(&mut self.known);
+        match self.pinned_register(dst) {
+            Some(pinned) => self.writer().emit_mov_reg(pinned, src),
+            None => self.mov(
+                Operand::Mem64BaseAndOffset(Reg::RegisterArrayBase, dst.0),
+                Operand::Reg(src),
+            ),
+        }
+        self.known = known.after_store(src, Slot::Register(dst));
     }
 
-    pub fn load_vm_register(&mut self, dst: Reg, src: VMRegister) {
-        self.mov(
-            Operand::Reg(dst),
-            Operand::Mem64BaseAndOffset(Reg::RegisterArrayBase, src.0),
-        );
+    fn load_vm_register(&mut self, dst: Reg, src: VMRegister) {
+        if self.known.holds(dst, Slot::Register(src)) {
+            return;
+        }
+
+        let known = std::mem::take(&mut self.known);
+        match self.pinned_register(src) {
+            Some(pinned) => self.writer().emit_mov_reg(dst, pinned),
+            None => self.mov(
+                Operand::Reg(dst),
+                Operand::Mem64BaseAndOffset(Reg::RegisterArrayBase, src.0),
+            ),
+        }
+        self.known = known.after_load(dst, Slot::Register(src));
     }
 
-    pub fn store_vm_local(&mut self, dst: VMLocal, src: Reg) {
+    fn store_vm_local(&mut self, dst: VMLocal, src: Reg) {
+        let known = std::mem::take(&mut self.known);
         self.mov(
             Operand::Mem64BaseAndOffset(Reg::LocalsArrayBase, dst.0),
             Operand::Reg(src),
         );
+        self.known = known.after_store(src, Slot::Local(dst));
     }
 
-    pub fn load_vm_local(&mut self, dst: Reg, src: VMLocal) {
+    fn load_vm_local(&mut self, dst: Reg, src: VMLocal) {
+        if self.known.holds(dst, Slot::Local(src)) {
+            return;
+        }
+
+        let known = std::mem::take(&mut self.known);
         self.mov(
             Operand::Reg(dst),
             Operand::Mem64BaseAndOffset(Reg::LocalsArrayBase, src.0),
         );
+        self.known = known.after_load(dst, Slot::Local(src));
     }
 
-    pub fn increment(&mut self, dst: Reg) {
+    fn increment(&mut self, dst: Reg) {
         // // Add 1 to the value in dst register
         self.writer().emit_incr(dst);
     }
 
-    pub fn less_than(&mut self, dst: Reg, src: Reg) {
-        // // Compare src and dst registers
-        self.writer().emit_cmp(src, Operand::Reg(dst));
+    fn binary_op(&mut self, op: BinaryOp, dst: Reg, src: Reg) {
+        match op {
+            BinaryOp::Add => self.writer().emit_add_reg(dst, dst, src),
+            BinaryOp::Sub => self.writer().emit_sub_reg(dst, dst, src),
+            BinaryOp::Mul => self.writer().emit_mul(dst, dst, src),
+            BinaryOp::Div | BinaryOp::Rem => {
+                // UDIV yields zero rather than faulting on a zero divisor, so check for it
+                self.writer().emit_cmp(src, Operand::Imm64(0));
+                self.trap_unless(Cond::NE, trap::DIVISION_BY_ZERO);
+
+                match op {
+                    BinaryOp::Div => self.writer().emit_udiv(dst, dst, src),
+                    _ => {
+                        // dst - (dst / src) * src
+                        self.writer().emit_udiv(Reg::GPR2, dst, src);
+                        self.writer().emit_msub(dst, Reg::GPR2, src, dst);
+                    }
+                }
+            }
+        }
+    }
+
+    fn compare(&mut self, cond: Comparison, dst: Reg, src: Reg) {
+        // Set the flags from dst - src
+        self.writer().emit_cmp(dst, Operand::Reg(src));
 
-        // Set dst to 1 if src < dst, else set it to 0
-        self.writer().emit_cset(dst);
+        // Set dst to 1 if dst <cond> src, else set it to 0
+        self.writer().emit_cset(dst, cond.into());
+        self.known.flags = Some((dst, cond.into()));
     }
 
-    pub fn jump(&mut self, target: &BlockTarget) {
-        // Branch to the target basic block (26-bit offset)
-        self.writer().emit_branch(0xdeadaf);
-        target.insert_jump_marker(self.len());
+    fn begin_block(&mut self) {
+        // Blocks can be entered from anywhere, so nothing is known about the machine state
+        self.known = Known::default();
     }
 
-    pub fn jump_conditional(
-        &mut self,
-        reg: Reg,
-        true_target: &BlockTarget,
-        false_target: &BlockTarget,
-    ) {
-        // Compare reg with zero
+    fn pin_vm_registers(&mut self, count: usize) {
+        self.pinned = count.min(PINNED_REGISTERS.len());
+    }
+
+    fn prologue(&mut self) {
+        // Push a frame record, saving the callee-saved registers used to unwind back here, to
+        // track the operand stack and to hold pinned VM registers
+        let frame_size = self.entry_frame_size();
+        self.writer()
+            .emit_stp_pre(Reg::FP, Reg::RET, Reg::SP, -frame_size);
+        self.writer()
+            .emit_stp_offset(Reg::EntryFrame, Reg::StackDepth, Reg::SP, 16);
+        for (pair, offset) in self.pinned_pairs() {
+            self.writer()
+                .emit_stp_offset(pair[0], pair[1], Reg::SP, offset);
+        }
+        for (i, pinned) in PINNED_REGISTERS.into_iter().enumerate().take(self.pinned) {
+            self.writer().emit_ldr(pinned, Reg::RegisterArrayBase, i);
+        }
+        self.writer().emit_add(Reg::FP, Reg::SP, 0);
+        self.writer().emit_add(Reg::EntryFrame, Reg::SP, 0);
+
+        assert_eq!(VM::STACK_DEPTH_OFFSET % 8, 0);
+        self.writer().emit_ldr(
+            Reg::StackDepth,
+            Reg::VmStructBase,
+            VM::STACK_DEPTH_OFFSET / 8,
+        );
+    }
+
+    fn epilogue(&mut self) {
+        self.write_back_pinned_registers();
+        self.writer().emit_str(
+            Reg::VmStructBase,
+            VM::STACK_DEPTH_OFFSET / 8,
+            Reg::StackDepth,
+        );
+
+        // Drop every frame pushed since the prologue, then restore what it saved
+        self.writer().emit_add(Reg::SP, Reg::EntryFrame, 0);
+        for (pair, offset) in self.pinned_pairs() {
+            self.writer()
+                .emit_ldp_offset(pair[0], pair[1], Reg::SP, offset);
+        }
+        self.writer()
+            .emit_ldp_offset(Reg::EntryFrame, Reg::StackDepth, Reg::SP, 16);
+        let frame_size = self.entry_frame_size();
+        self.writer()
+            .emit_ldp_post(Reg::FP, Reg::RET, Reg::SP, frame_size);
+        self.writer().emit_ret();
+    }
+
+    fn reserve_frames(&mut self) {
+        // SP can't be the operand of a shifted register SUB, so go through GPR1
+        assert_eq!(VM::ENTRY_DEPTH_OFFSET % 8, 0);
+        self.writer()
+            .emit_ldr(Reg::GPR0, Reg::VmStructBase, VM::ENTRY_DEPTH_OFFSET / 8);
+        self.writer().emit_add(Reg::GPR1, Reg::SP, 0);
+        self.writer()
+            .emit_sub_reg_lsl(Reg::GPR1, Reg::GPR1, Reg::GPR0, 4);
+        self.writer().emit_add(Reg::SP, Reg::GPR1, 0);
+    }
+
+    fn store_result(&mut self, src: Reg) {
+        assert_ne!(src, Reg::GPR2);
+        assert_eq!(VM::RESULT_OFFSET % 8, 0);
+        let offset = VM::RESULT_OFFSET / 8;
+        self.writer().emit_mov_imm(Reg::GPR2, 1);
+        self.writer().emit_str(Reg::VmStructBase, offset, Reg::GPR2);
+        self.writer().emit_str(Reg::VmStructBase, offset + 1, src);
+    }
+
+    fn trap_handler(&mut self) {
+        // Traps branch here with the value to record in GPR2
+        self.trap_handler = Some(self.len());
+        assert_eq!(VM::TRAP_OFFSET % 8, 0);
+        self.writer()
+            .emit_str(Reg::VmStructBase, VM::TRAP_OFFSET / 8, Reg::GPR2);
+
+        // The link register holds the return address of the innermost call, and each call
+        // pushed a frame record saving the one before it between SP and the entry frame, so
+        // copy the link register and then walk the frame records from the innermost
+        let return_addresses = VM::RETURN_ADDRESSES_OFFSET as u16;
+        assert!(return_addresses < 1 << 12);
+        self.writer()
+            .emit_add(Reg::GPR1, Reg::VmStructBase, return_addresses);
+        self.writer().emit_str(Reg::GPR1, 0, Reg::RET);
+        self.writer().emit_add(Reg::GPR1, Reg::GPR1, 8);
+        self.writer().emit_add(Reg::GPR0, Reg::SP, 0);
+        let next_frame = self.len();
+        self.writer()
+            .emit_cmp(Reg::GPR0, Operand::Reg(Reg::EntryFrame));
+        let done = self.len();
+        self.writer().emit_branch_cond(Cond::HS, 0);
+        self.writer().emit_ldr(Reg::GPR2, Reg::GPR0, 1);
+        self.writer().emit_str(Reg::GPR1, 0, Reg::GPR2);
+        self.writer().emit_add(Reg::GPR1, Reg::GPR1, 8);
+        self.writer().emit_add(Reg::GPR0, Reg::GPR0, 16);
+        let branch = self.len();
+        self.writer().emit_branch(0);
+        self.link_jump(next_frame, branch)
+            .expect("the loop is smaller than the range of B");
+        self.link_jump(self.len(), done)
+            .expect("the loop is smaller than the range of B.cond");
+
+        self.epilogue();
+    }
+
+    fn set_trap_site(&mut self, site: usize) {
+        self.trap_site = site;
+    }
+
+    fn trap(&mut self, code: u8) {
+        let handler = self
+            .trap_handler
+            .expect("the trap handler should be emitted before code that can trap");
+        let value = trap::encode(code, self.trap_site);
+        self.writer().emit_mov_imm(Reg::GPR2, value);
+
+        let branch = self.len();
+        self.writer().emit_branch(0);
+        self.link_jump(handler, branch)
+            .expect("the trap handler should be in range of B");
+    }
+
+    fn store_trap(&mut self, code: u8) {
+        let value = trap::encode(code, self.trap_site);
+        self.writer().emit_mov_imm(Reg::GPR2, value);
+        self.writer()
+            .emit_str(Reg::VmStructBase, VM::TRAP_OFFSET / 8, Reg::GPR2);
+    }
+
+    fn consume_fuel(&mut self) {
+        assert_eq!(VM::FUEL_OFFSET % 8, 0);
+        let offset = VM::FUEL_OFFSET / 8;
+        self.writer().emit_ldr(Reg::GPR0, Reg::VmStructBase, offset);
+        self.writer().emit_cmp(Reg::GPR0, Operand::Imm64(0));
+        self.trap_unless(Cond::NE, trap::OUT_OF_FUEL);
+
+        self.writer().emit_sub(Reg::GPR0, Reg::GPR0, 1);
+        self.writer().emit_str(Reg::VmStructBase, offset, Reg::GPR0);
+    }
+
+    fn increment_counter(&mut self, index: usize) {
+        self.writer().emit_mov_imm(Reg::GPR1, index as u64);
+        self.increment_counter_at_gpr1();
+    }
+
+    fn count_branch(&mut self, reg: Reg, taken: usize) {
+        assert!(reg != Reg::GPR1 && reg != Reg::GPR2);
+        // The not taken counter follows the taken one, so index it by adding whether reg is zero
         self.writer().emit_cmp(reg, Operand::Imm64(0));
+        self.writer().emit_cset(Reg::GPR1, Cond::EQ);
+        self.writer().emit_mov_imm(Reg::GPR2, taken as u64);
+        self.writer().emit_add_reg(Reg::GPR1, Reg::GPR1, Reg::GPR2);
+        self.increment_counter_at_gpr1();
+    }
+
+    fn enter_frame(&mut self) {
+        // Each frame record takes 16 bytes of stack, so trap unless there's room for another
+        self.writer().emit_add(Reg::GPR2, Reg::SP, 0);
+        self.writer()
+            .emit_sub_reg(Reg::GPR2, Reg::EntryFrame, Reg::GPR2);
+        let max_stack_size = (MAX_CALL_DEPTH * 16) as u64;
+        self.writer()
+            .emit_cmp(Reg::GPR2, Operand::Imm64(max_stack_size));
+        self.trap_unless(Cond::LO, trap::CALL_STACK_OVERFLOW);
+
+        // Advance the locals base past the caller's window of locals
+        self.load_frame_size(Reg::GPR2);
+        self.writer()
+            .emit_add_reg_lsl(Reg::LocalsArrayBase, Reg::LocalsArrayBase, Reg::GPR2, 3);
+    }
+
+    fn leave_frame(&mut self) {
+        self.load_frame_size(Reg::GPR2);
+        self.writer()
+            .emit_sub_reg_lsl(Reg::LocalsArrayBase, Reg::LocalsArrayBase, Reg::GPR2, 3);
+    }
+
+    fn call(&mut self, target: BlockTarget) -> usize {
+        // Push a frame record so the caller's link register survives the call
+        self.writer().emit_stp_pre(Reg::FP, Reg::RET, Reg::SP, -16);
+        self.writer().emit_add(Reg::FP, Reg::SP, 0);
+
+        self.writer().emit_branch_link(0xdeadaf);
+        self.jumps.push((self.len() - 4, target));
+        let return_address = self.len();
+
+        self.writer().emit_ldp_post(Reg::FP, Reg::RET, Reg::SP, 16);
+        return_address
+    }
+
+    fn push(&mut self, src: Reg) {
+        assert_ne!(src, Reg::GPR2);
+        self.writer()
+            .emit_cmp(Reg::StackDepth, Operand::Imm64(MAX_STACK_DEPTH as u64));
+        self.trap_unless(Cond::LO, trap::STACK_OVERFLOW);
+
+        self.stack_top_address(Reg::GPR2);
+        self.writer().emit_str(Reg::GPR2, 0, src);
+        self.writer().emit_add(Reg::StackDepth, Reg::StackDepth, 1);
+    }
+
+    fn pop(&mut self, dst: Reg) {
+        self.writer().emit_cmp(Reg::StackDepth, Operand::Imm64(0));
+        self.trap_unless(Cond::NE, trap::STACK_UNDERFLOW);
 
-        // Branch to false_target if reg is zero
-        self.writer().emit_branch_eq(0xdead); // Replace with the correct branch offset
-        false_target.insert_jump_marker(self.len());
+        self.writer().emit_sub(Reg::StackDepth, Reg::StackDepth, 1);
+        self.stack_top_address(Reg::GPR2);
+        self.writer().emit_ldr(dst, Reg::GPR2, 0);
+    }
+
+    fn jump(&mut self, target: BlockTarget) {
+        // Branch to the target basic block (26-bit offset)
+        self.writer().emit_branch(0xdeadaf);
+        self.jumps.push((self.len() - 4, target));
+    }
+
+    fn jump_conditional(&mut self, reg: Reg, true_target: BlockTarget, false_target: BlockTarget) {
+        // Branch to false_target if reg is zero, which is linked once its offset is known. When
+        // reg was just set by CSET, branching on the inverse of its condition skips the CMP.
+        match self.known.flags {
+            Some((flags_reg, cond)) if flags_reg == reg => {
+                self.writer().emit_branch_cond(cond.invert(), 0);
+            }
+            _ => {
+                self.writer().emit_cmp(reg, Operand::Imm64(0));
+                self.writer().emit_branch_cond(Cond::EQ, 0);
+            }
+        }
+        let branch = self.len() - 4;
+        self.pending_branches.push((branch, false_target));
 
         // Branch to true_target (unconditionally)
         self.jump(true_target);
     }
 
-    pub fn call_into_rust(&mut self, dst: Reg, func: Func) {
-        match func {
-            Func::FnSingleInt64WithReturnInt64(func, arg0) => {
-                let addr = func as *const () as u64;
-                self.writer().emit_push(Reg::VmStructBase);
-                self.writer().emit_push(Reg::RegisterArrayBase);
-                self.writer().emit_push(Reg::LocalsArrayBase);
-                self.writer().emit_push(Reg::GPR0);
-                self.writer().emit_push(Reg::GPR1);
-                self.writer().emit_push(Reg::RET);
-
-                self.writer().emit_mov_imm(Reg::VmStructBase, arg0);
-                self.writer().emit_mov_imm(Reg::GPR1, addr);
-                self.writer().emit_branch_with_link(Reg::GPR1);
-                self.writer().emit_mov_reg(Reg::GPR0, Reg::VmStructBase);
-
-                self.writer().emit_pop(Some(Reg::RET));
-                self.writer().emit_pop(Some(Reg::GPR1));
-                if dst != Reg::GPR0 {
-                    self.writer().emit_mov_reg(dst, Reg::GPR0);
-                    self.writer().emit_pop(Some(Reg::GPR0));
-                } else {
-                    self.writer().emit_pop(None);
-                }
-                self.writer().emit_pop(Some(Reg::LocalsArrayBase));
-                self.writer().emit_pop(Some(Reg::RegisterArrayBase));
-                self.writer().emit_pop(Some(Reg::VmStructBase));
-            }
+    fn call_into_rust(&mut self, dst: Reg, func: Func) {
+        self.host_calls.push(func);
+
+        // Pinned registers survive the call, but the host may look at the VM's copy
+        self.write_back_pinned_registers();
+
+        // AAPCS64 lets the callee clobber x0-x18 and the link register, so save the ones
+        // holding VM state, keeping the stack 16-byte aligned
+        self.writer()
+            .emit_stp_pre(Reg::VmStructBase, Reg::RegisterArrayBase, Reg::SP, -48);
+        self.writer()
+            .emit_stp_offset(Reg::LocalsArrayBase, Reg::StackArrayBase, Reg::SP, 16);
+        self.writer().emit_str(Reg::SP, 4, Reg::RET);
+
+        // Arguments come from r1 onwards into x0-x2, where x1 is loaded last as it holds the
+        // register array base
+        let args = [
+            (Reg::VmStructBase, 1),
+            (Reg::LocalsArrayBase, 3),
+            (Reg::RegisterArrayBase, 2),
+        ];
+        for (arg, reg) in args.into_iter().filter(|(_, reg)| *reg <= func.arity()) {
+            self.writer().emit_ldr(arg, Reg::RegisterArrayBase, reg);
         }
+
+        self.writer().emit_mov_imm(Reg::GPR2, func.address());
+        self.writer().emit_branch_with_link(Reg::GPR2);
+        self.writer().emit_mov_reg(dst, Reg::VmStructBase);
+
+        self.writer().emit_ldr(Reg::RET, Reg::SP, 4);
+        self.writer()
+            .emit_ldp_offset(Reg::LocalsArrayBase, Reg::StackArrayBase, Reg::SP, 16);
+        self.writer()
+            .emit_ldp_post(Reg::VmStructBase, Reg::RegisterArrayBase, Reg::SP, 48);
     }
 
-    pub fn brk(&mut self) {
+    fn brk(&mut self) {
+        // Let a debugger see the latest values of pinned registers
+        self.write_back_pinned_registers();
         self.writer().emit_brk(0);
     }
 
-    pub fn ret(&mut self) {
+    fn ret(&mut self) {
         // Return from the function
         self.writer().emit_ret();
     }
 
-    pub fn no_op(&mut self) {
+    fn no_op(&mut self) {
         self.writer().emit_nop();
     }
 
-    pub fn rewrite_instr32(&mut self, offset: usize, value: u32) {
+    fn host_calls(&self) -> &[Func] {
+        &self.host_calls
+    }
+
+    fn emit_veneers_if_needed(&mut self) {
+        let Some((oldest, _)) = self.pending_branches.first() else {
+            return;
+        };
+
+        // Leave room for the island itself, and for whatever is emitted before the next check
+        let island_end = self.len() + 4 * (self.pending_branches.len() + 1);
+        if island_end + VENEER_ISLAND_MARGIN < oldest + COND_BRANCH_RANGE {
+            return;
+        }
+
+        // Islands can land in the middle of a block, so branch over them
+        let island = self.len();
+        self.writer().emit_branch(0);
+        for (branch, target) in std::mem::take(&mut self.pending_branches) {
+            self.emit_veneer(branch, target)
+                .expect("islands are emitted while every pending branch can reach them");
+        }
+        self.link_jump(self.len(), island)
+            .expect("islands are smaller than the range of B");
+    }
+
+    fn resolve_pending_branches(&mut self, offsets: &[usize]) -> Result<(), LinkError> {
+        for (branch, target) in std::mem::take(&mut self.pending_branches) {
+            match offsets[target.0].abs_diff(branch) < COND_BRANCH_RANGE {
+                true => self.jumps.push((branch, target)),
+                false => self.emit_veneer(branch, target)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn take_jumps(&mut self) -> Vec<(usize, BlockTarget)> {
+        std::mem::take(&mut self.jumps)
+    }
+
+    fn link_jump(&mut self, target_offset: usize, instr_offset: usize) -> Result<(), LinkError> {
+        const OP_JMP: u32 = 0b000101;
+        const OP_CALL: u32 = 0b100101;
+        const OP_JCOND: u32 = 0b01010100;
+        const IMM26_MASK: u32 = (1 << 26) - 1;
+        const IMM19_MASK: u32 = ((1 << 19) - 1) << 5;
+
+        let instr = &self[instr_offset..instr_offset + 4];
+        let instr = u32::from_le_bytes(instr.try_into().unwrap());
+
+        // Branch offsets are counted in instructions from the branch itself
+        let offset = (target_offset as i64 - instr_offset as i64) / 4;
+        let out_of_range = LinkError::OutOfRange {
+            instr_offset,
+            target_offset,
+        };
+
+        let linked = if matches!(instr >> 26, OP_JMP | OP_CALL) {
+            let imm26 = signed_field(offset, 26).ok_or(out_of_range)?;
+            (instr & !IMM26_MASK) | imm26
+        } else if instr >> 24 == OP_JCOND {
+            let imm19 = signed_field(offset, 19).ok_or(out_of_range)?;
+            (instr & !IMM19_MASK) | (imm19 << 5)
+        } else {
+            return Err(LinkError::NotABranch { instr_offset });
+        };
+
+        self.rewrite_instr32(instr_offset, linked);
+        Ok(())
+    }
+}
+
+impl Assembler {
+    /// Emits a `B` to `target` for the conditional branch at `branch` to go via
+    fn emit_veneer(&mut self, branch: usize, target: BlockTarget) -> Result<(), LinkError> {
+        let veneer = self.len();
+        self.writer().emit_branch(0);
+        self.jumps.push((veneer, target));
+        self.link_jump(veneer, branch)
+    }
+
+    fn rewrite_instr32(&mut self, offset: usize, value: u32) {
         for i in 0..4 {
             self.output[offset + i] = ((value >> (i * 8)) & 0xff) as u8;
         }
@@ -171,7 +665,66 @@ impl Assembler {
         }
     }
 
-    fn writer(&mut self) -> Arm64Writer {
+    /// The host register holding `reg`, if it is pinned
+    fn pinned_register(&self, reg: VMRegister) -> Option<Reg> {
+        PINNED_REGISTERS[..self.pinned].get(reg.0).copied()
+    }
+
+    /// Copies every pinned register into `VM::registers`
+    fn write_back_pinned_registers(&mut self) {
+        for (i, pinned) in PINNED_REGISTERS.into_iter().enumerate().take(self.pinned) {
+            self.writer().emit_str(Reg::RegisterArrayBase, i, pinned);
+        }
+    }
+
+    /// The pairs of pinned registers the entry frame saves, and where it saves each pair
+    fn pinned_pairs(&self) -> Vec<(&'static [Reg], i16)> {
+        let pairs = PINNED_REGISTERS[..self.pinned.next_multiple_of(2)].chunks(2);
+        pairs.zip((32..).step_by(16)).collect()
+    }
+
+    /// The bytes pushed by the prologue, which must keep the stack 16-byte aligned
+    fn entry_frame_size(&self) -> i16 {
+        32 + 16 * self.pinned_pairs().len() as i16
+    }
+
+    fn load_frame_size(&mut self, dst: Reg) {
+        assert_eq!(VM::FRAME_SIZE_OFFSET % 8, 0);
+        self.writer()
+            .emit_ldr(dst, Reg::VmStructBase, VM::FRAME_SIZE_OFFSET / 8);
+    }
+
+    /// Sets `dst` to the address of the first free slot of the operand stack
+    fn stack_top_address(&mut self, dst: Reg) {
+        self.writer()
+            .emit_add_reg_lsl(dst, Reg::StackArrayBase, Reg::StackDepth, 3);
+    }
+
+    /// Adds one to the counter GPR1 indexes, going through GPR2
+    fn increment_counter_at_gpr1(&mut self) {
+        assert_eq!(VM::COUNTERS_BASE_OFFSET % 8, 0);
+        self.writer()
+            .emit_ldr(Reg::GPR2, Reg::VmStructBase, VM::COUNTERS_BASE_OFFSET / 8);
+        self.writer()
+            .emit_add_reg_lsl(Reg::GPR2, Reg::GPR2, Reg::GPR1, 3);
+        self.writer().emit_ldr(Reg::GPR1, Reg::GPR2, 0);
+        self.writer().emit_add(Reg::GPR1, Reg::GPR1, 1);
+        self.writer().emit_str(Reg::GPR2, 0, Reg::GPR1);
+    }
+
+    /// Branches over a trap with `code` when `cond` holds
+    fn trap_unless(&mut self, cond: Cond, code: u8) {
+        let skip = self.len();
+        self.writer().emit_branch_cond(cond, 0);
+        self.trap(code);
+        self.link_jump(self.len(), skip)
+            .expect("traps are smaller than the range of B.cond");
+    }
+
+    /// Gives access to the instruction encoders, forgetting what the peephole optimizer knew as
+    /// the instructions written could change anything
+    fn writer(&mut self) -> Arm64Writer<'_> {
+        self.known = Known::default();
         Arm64Writer(&mut self.output)
     }
 }
@@ -266,7 +819,7 @@ impl<'a> Arm64Writer<'a> {
             .unwrap();
 
             hw += 1;
-            imm = imm >> 16;
+            imm >>= 16;
         }
     }
 
@@ -318,15 +871,15 @@ impl<'a> Arm64Writer<'a> {
         .unwrap();
     }
 
-    pub fn emit_cset(&mut self, dst: Reg) {
-        // CSET <Xd>, <cond>
+    pub fn emit_cset(&mut self, dst: Reg, cond: Cond) {
+        // CSET <Xd>, <cond> (alias of CSINC <Xd>, XZR, XZR with the inverted condition)
         self.emit32_gen(|idx| match idx {
             0 => Some(BitIndex {
                 value: 0b1001101010011111,
                 bits: 16,
             }),
             1 => Some(BitIndex {
-                value: 0b1101, // cond = LT (Signed less than)
+                value: (cond as usize) ^ 1,
                 bits: 4,
             }),
             2 => Some(BitIndex {
@@ -390,16 +943,160 @@ impl<'a> Arm64Writer<'a> {
         .unwrap();
     }
 
-    pub fn emit_push(&mut self, src: Reg) {
-        self.emit_sub(Reg::SP, Reg::SP, 64); // 64-bit
-        self.emit_str(Reg::SP, 1, src);
+    pub fn emit_add_reg(&mut self, dst: Reg, lhs: Reg, rhs: Reg) {
+        // ADD (shifted register)
+        self.emit_data_processing_3reg(0b10001011000, dst, lhs, rhs, 0);
     }
 
-    pub fn emit_pop(&mut self, dst: Option<Reg>) {
-        if let Some(dst) = dst {
-            self.emit_ldr(dst, Reg::SP, 1);
-        }
-        self.emit_add(Reg::SP, Reg::SP, 64); // 64-bit
+    pub fn emit_sub_reg(&mut self, dst: Reg, lhs: Reg, rhs: Reg) {
+        // SUB (shifted register)
+        self.emit_data_processing_3reg(0b11001011000, dst, lhs, rhs, 0);
+    }
+
+    pub fn emit_add_reg_lsl(&mut self, dst: Reg, lhs: Reg, rhs: Reg, shift: usize) {
+        // ADD (shifted register), LSL #shift
+        self.emit_data_processing_3reg(0b10001011000, dst, lhs, rhs, shift);
+    }
+
+    pub fn emit_sub_reg_lsl(&mut self, dst: Reg, lhs: Reg, rhs: Reg, shift: usize) {
+        // SUB (shifted register), LSL #shift
+        self.emit_data_processing_3reg(0b11001011000, dst, lhs, rhs, shift);
+    }
+
+    pub fn emit_udiv(&mut self, dst: Reg, lhs: Reg, rhs: Reg) {
+        // UDIV
+        self.emit_data_processing_3reg(0b10011010110, dst, lhs, rhs, 0b000010);
+    }
+
+    pub fn emit_mul(&mut self, dst: Reg, lhs: Reg, rhs: Reg) {
+        // MUL <Xd>, <Xn>, <Xm> (alias of MADD with XZR as the addend)
+        self.emit_multiply_add(dst, lhs, rhs, 0b11111, false);
+    }
+
+    pub fn emit_msub(&mut self, dst: Reg, lhs: Reg, rhs: Reg, minuend: Reg) {
+        // MSUB <Xd>, <Xn>, <Xm>, <Xa> (dst = minuend - lhs * rhs)
+        self.emit_multiply_add(dst, lhs, rhs, minuend as usize, true);
+    }
+
+    fn emit_data_processing_3reg(
+        &mut self,
+        op_code: usize,
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+        op2: usize,
+    ) {
+        self.emit32_gen(|idx| match idx {
+            0 => Some(BitIndex {
+                value: op_code,
+                bits: 11,
+            }),
+            1 => Some(BitIndex {
+                value: rhs as usize,
+                bits: 5,
+            }),
+            2 => Some(BitIndex {
+                value: op2,
+                bits: 6,
+            }),
+            3 => Some(BitIndex {
+                value: lhs as usize,
+                bits: 5,
+            }),
+            4 => Some(BitIndex {
+                value: dst as usize,
+                bits: 5,
+            }),
+            _ => None,
+        })
+        .unwrap();
+    }
+
+    fn emit_multiply_add(&mut self, dst: Reg, lhs: Reg, rhs: Reg, addend: usize, sub: bool) {
+        // MADD/MSUB
+        self.emit32_gen(|idx| match idx {
+            0 => Some(BitIndex {
+                value: 0b10011011000,
+                bits: 11,
+            }),
+            1 => Some(BitIndex {
+                value: rhs as usize,
+                bits: 5,
+            }),
+            2 => Some(BitIndex {
+                value: sub as usize,
+                bits: 1,
+            }),
+            3 => Some(BitIndex {
+                value: addend,
+                bits: 5,
+            }),
+            4 => Some(BitIndex {
+                value: lhs as usize,
+                bits: 5,
+            }),
+            5 => Some(BitIndex {
+                value: dst as usize,
+                bits: 5,
+            }),
+            _ => None,
+        })
+        .unwrap();
+    }
+
+    pub fn emit_stp_pre(&mut self, first: Reg, second: Reg, base: Reg, offset: i16) {
+        // STP <Xt1>, <Xt2>, [<Xn|SP>, #<imm>]!
+        self.emit_load_store_pair(0b1010100110, first, second, base, offset);
+    }
+
+    pub fn emit_ldp_post(&mut self, first: Reg, second: Reg, base: Reg, offset: i16) {
+        // LDP <Xt1>, <Xt2>, [<Xn|SP>], #<imm>
+        self.emit_load_store_pair(0b1010100011, first, second, base, offset);
+    }
+
+    pub fn emit_stp_offset(&mut self, first: Reg, second: Reg, base: Reg, offset: i16) {
+        // STP <Xt1>, <Xt2>, [<Xn|SP>, #<imm>]
+        self.emit_load_store_pair(0b1010100100, first, second, base, offset);
+    }
+
+    pub fn emit_ldp_offset(&mut self, first: Reg, second: Reg, base: Reg, offset: i16) {
+        // LDP <Xt1>, <Xt2>, [<Xn|SP>, #<imm>]
+        self.emit_load_store_pair(0b1010100101, first, second, base, offset);
+    }
+
+    fn emit_load_store_pair(
+        &mut self,
+        op_code: usize,
+        first: Reg,
+        second: Reg,
+        base: Reg,
+        offset: i16,
+    ) {
+        assert_eq!(offset % 8, 0);
+        self.emit32_gen(|idx| match idx {
+            0 => Some(BitIndex {
+                value: op_code,
+                bits: 10,
+            }),
+            1 => Some(BitIndex {
+                value: ((offset / 8) as usize) & 0x7f,
+                bits: 7,
+            }),
+            2 => Some(BitIndex {
+                value: second as usize,
+                bits: 5,
+            }),
+            3 => Some(BitIndex {
+                value: base as usize,
+                bits: 5,
+            }),
+            4 => Some(BitIndex {
+                value: first as usize,
+                bits: 5,
+            }),
+            _ => None,
+        })
+        .unwrap();
     }
 
     pub fn emit_branch(&mut self, addr_offset: usize) {
@@ -419,6 +1116,23 @@ impl<'a> Arm64Writer<'a> {
         .unwrap();
     }
 
+    pub fn emit_branch_link(&mut self, addr_offset: usize) {
+        // BL (Branch with Link)
+        // Call target (26-bit offset)
+        self.emit32_gen(|idx| match idx {
+            0 => Some(BitIndex {
+                value: 0b100101,
+                bits: 6,
+            }),
+            1 => Some(BitIndex {
+                value: addr_offset,
+                bits: 26,
+            }),
+            _ => None,
+        })
+        .unwrap();
+    }
+
     pub fn emit_branch_with_link(&mut self, target: Reg) {
         // BLR (Branch with Link to Register)
         self.emit32_gen(|idx| match idx {
@@ -436,8 +1150,8 @@ impl<'a> Arm64Writer<'a> {
         .unwrap();
     }
 
-    pub fn emit_branch_eq(&mut self, imm19: usize) {
-        // B.cond (cond = EQ)
+    pub fn emit_branch_cond(&mut self, cond: Cond, imm19: usize) {
+        // B.cond
         self.emit32_gen(|idx| match idx {
             0 => Some(BitIndex {
                 value: 0b01010100,
@@ -447,7 +1161,10 @@ impl<'a> Arm64Writer<'a> {
                 value: imm19,
                 bits: 19,
             }),
-            2 => Some(BitIndex { value: 0, bits: 5 }),
+            2 => Some(BitIndex {
+                value: cond as usize,
+                bits: 5,
+            }),
             _ => None,
         })
         .unwrap();
@@ -585,6 +1302,15 @@ impl<'a> Arm64Writer<'a> {
 }
 pub struct BitwiseWriter;
 
+/// Encodes `value` as a two's complement field `bits` wide, if it fits
+fn signed_field(value: i64, bits: u32) -> Option<u32> {
+    let limit = 1 << (bits - 1);
+    let mask = (1 << bits) - 1;
+    (-limit..limit)
+        .contains(&value)
+        .then_some(value as u32 & mask)
+}
+
 impl BitwiseWriter {
     pub fn write(mut generator: impl FnMut(usize) -> Option<BitIndex>) -> Result<u32, ()> {
         let mut bit_position = 0;
@@ -619,3 +1345,78 @@ impl BitwiseWriter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        differential,
+        jit::{Jit, Options, Target},
+        testing,
+    };
+
+    /// A program whose conditional jump to `END` has to reach over a block of more than
+    /// `COND_BRANCH_RANGE` bytes, taking the far branch unless `condition` is non-zero
+    fn far_branch_program(condition: u64) -> String {
+        let mut code = format!("ENTRY:\n  LOAD_INT32 {condition}\n  JUMP_EITHER #BIG #END\n");
+        code.push_str("BIG:\n");
+        code.push_str(&"  INCR\n".repeat(300_000));
+        code.push_str("  JUMP #END\nEND:\n  STORE_REG r1\n  RET\n");
+        code
+    }
+
+    #[test]
+    fn conditional_branches_reach_far_blocks_through_veneers() {
+        for condition in [0, 1] {
+            let program = testing::parse(&far_branch_program(condition));
+            let jit = Jit::compile_for(&program, Target::Aarch64).unwrap();
+            assert!(jit.assembler.len() > COND_BRANCH_RANGE);
+
+            let options = Options::new(Target::Aarch64);
+            let report = differential::cross_check(&program, &testing::vm(), options).unwrap();
+            assert!(report.is_match(), "condition {condition}: {report}");
+        }
+    }
+
+    #[test]
+    fn link_jump_rejects_targets_out_of_range() {
+        let mut assembler = Assembler::default();
+        assembler.writer().emit_branch_cond(Cond::EQ, 0);
+        assembler.writer().emit_branch(0);
+        assembler.writer().emit_nop();
+        let unlinked = assembler.to_vec();
+
+        // B.cond reaches 2^18 instructions either way, B reaches 2^25
+        let (cond_branch, branch) = (0, 4);
+        assert_eq!(
+            assembler.link_jump(COND_BRANCH_RANGE, cond_branch),
+            Err(LinkError::OutOfRange {
+                instr_offset: cond_branch,
+                target_offset: COND_BRANCH_RANGE,
+            })
+        );
+        assert_eq!(
+            assembler.link_jump(branch + (1 << 27), branch),
+            Err(LinkError::OutOfRange {
+                instr_offset: branch,
+                target_offset: branch + (1 << 27),
+            })
+        );
+        assert_eq!(
+            assembler.to_vec(),
+            unlinked,
+            "failed links leave code as it was"
+        );
+
+        assert_eq!(
+            assembler.link_jump(COND_BRANCH_RANGE - 4, cond_branch),
+            Ok(())
+        );
+        assert_eq!(assembler.link_jump(branch + (1 << 27) - 4, branch), Ok(()));
+        assert_eq!(assembler.link_jump(0, branch), Ok(()));
+        assert_eq!(
+            assembler.link_jump(0, 8),
+            Err(LinkError::NotABranch { instr_offset: 8 })
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    error::{ConfigError, LinkError},
+    host::Func,
+    vm::BlockTarget,
+    vm::Comparison,
+    vm::VMLocal,
+    vm::VMRegister,
+};
+
+use super::assembler::Reg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Aarch64,
+    X86_64,
+}
+
+impl Target {
+    pub fn host() -> Self {
+        if cfg!(target_arch = "x86_64") {
+            Target::X86_64
+        } else {
+            Target::Aarch64
+        }
+    }
+
+    /// Whether the host can run code for this target, either natively or in the emulator,
+    /// which only runs AArch64 code
+    pub fn is_runnable(self) -> bool {
+        self == Target::host() || self == Target::Aarch64
+    }
+
+    /// The target named by the `JIT_TARGET` environment variable, falling back to the host
+    /// architecture when unset. Code for a foreign target is emulated where possible.
+    pub fn configured() -> Result<Self, ConfigError> {
+        match std::env::var("JIT_TARGET") {
+            Ok(name) => Self::named(&name),
+            Err(_) => Ok(Target::host()),
+        }
+    }
+
+    /// The target called `name`, as long as the host can run code for it
+    fn named(name: &str) -> Result<Self, ConfigError> {
+        let target = match name {
+            "aarch64" => Some(Target::Aarch64),
+            "x86_64" => Some(Target::X86_64),
+            _ => None,
+        };
+        target
+            .filter(|x| x.is_runnable())
+            .ok_or_else(|| ConfigError {
+                variable: "JIT_TARGET",
+                value: name.to_string(),
+                expected: match Target::X86_64.is_runnable() {
+                    true => "aarch64 or x86_64",
+                    false => "aarch64",
+                },
+            })
+    }
+}
+
+/// A two operand arithmetic operation on unsigned 64-bit values, computing `dst = dst op src`.
+/// Division and remainder trap with `trap::DIVISION_BY_ZERO` when `src` is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+/// Codes that tell apart why compiled code trapped. The trap handler records them in the low
+/// byte of `VM::trap`, with the site set by `Backend::set_trap_site` in the bits above.
+pub mod trap {
+    use crate::error::TrapKind;
+
+    pub const CALL_STACK_OVERFLOW: u8 = 1;
+    pub const STACK_OVERFLOW: u8 = 2;
+    pub const STACK_UNDERFLOW: u8 = 3;
+    pub const DIVISION_BY_ZERO: u8 = 4;
+    pub const ABORT: u8 = 5;
+    /// Not an error, but raised like one so that metered code can stop once the VM runs out of
+    /// fuel with its state recorded by the trap handler
+    pub const OUT_OF_FUEL: u8 = 6;
+    /// Not an error either, but raised on entering a block that wasn't compiled so that the
+    /// interpreter can carry on from there
+    pub const SUSPEND: u8 = 7;
+    /// Recorded without the trap handler when the block compiled code was entered at returns,
+    /// so that the interpreter can carry on from its caller
+    pub const RETURNED: u8 = 8;
+
+    /// The value recorded in `VM::trap` for a trap with `code` at `site`
+    pub fn encode(code: u8, site: usize) -> u64 {
+        ((site as u64) << 8) | code as u64
+    }
+
+    /// Splits a value recorded in `VM::trap` back into the code and site of the trap
+    pub fn decode(trap: u64) -> (u8, usize) {
+        (trap as u8, (trap >> 8) as usize)
+    }
+
+    /// Why compiled code trapped with `code`
+    pub fn kind(code: u8) -> TrapKind {
+        match code {
+            CALL_STACK_OVERFLOW => TrapKind::CallStackOverflow,
+            STACK_OVERFLOW => TrapKind::StackOverflow,
+            STACK_UNDERFLOW => TrapKind::StackUnderflow,
+            DIVISION_BY_ZERO => TrapKind::DivisionByZero,
+            ABORT => TrapKind::Abort,
+            code => panic!("compiled code trapped with unknown code {code}"),
+        }
+    }
+}
+
+/// Machine code generator for a single target architecture.
+///
+/// `Reg` names logical registers: each backend maps them onto its own register file, with
+/// `VmStructBase`, `RegisterArrayBase`, `LocalsArrayBase` and `StackArrayBase` holding the four
+/// arguments of the compiled function as per the platform calling convention. `StackDepth`
+/// holds the operand stack depth between the prologue and epilogue.
+pub trait Backend: std::ops::Deref<Target = [u8]> {
+    fn load_immediate64(&mut self, dst: Reg, imm: u64);
+    fn store_vm_register(&mut self, dst: VMRegister, src: Reg);
+    fn load_vm_register(&mut self, dst: Reg, src: VMRegister);
+    fn store_vm_local(&mut self, dst: VMLocal, src: Reg);
+    fn load_vm_local(&mut self, dst: Reg, src: VMLocal);
+    fn increment(&mut self, dst: Reg);
+    fn binary_op(&mut self, op: BinaryOp, dst: Reg, src: Reg);
+    /// Sets `dst` to 1 when `dst <cond> src` holds, and to 0 otherwise
+    fn compare(&mut self, cond: Comparison, dst: Reg, src: Reg);
+    /// Marks where a basic block starts, which other code may branch to
+    fn begin_block(&mut self);
+    /// Keeps VM registers r0 up to `count` in host registers between the prologue and the
+    /// epilogue, or as many of them as the backend has spare registers for. Must be called
+    /// before any code is emitted.
+    fn pin_vm_registers(&mut self, count: usize);
+    /// Pushes the entry frame, saving any callee-saved registers the compiled code uses
+    fn prologue(&mut self);
+    /// Unwinds every frame pushed since the prologue and returns to the host
+    fn epilogue(&mut self);
+    /// Reserves a frame for each of the `VM::entry_depth` calls the interpreter was nested
+    /// within on entering compiled code, so that they count towards `MAX_CALL_DEPTH`. Emitted
+    /// straight after the prologue.
+    fn reserve_frames(&mut self);
+    /// Records `src` as the result the program exits with, for the epilogue to hand back
+    fn store_result(&mut self, src: Reg);
+    /// Emits the code every trap branches to, which records the trap in `VM::trap` and the
+    /// return address of every call it is nested within in `VM::return_addresses`, then unwinds
+    /// like the epilogue. Must be emitted before any code that can trap.
+    fn trap_handler(&mut self);
+    /// Tags the traps raised by code emitted from now on with `site`
+    fn set_trap_site(&mut self, site: usize);
+    /// Traps with `code`, one of the codes in `trap`
+    fn trap(&mut self, code: u8);
+    /// Records `code` in `VM::trap` without branching to the trap handler
+    fn store_trap(&mut self, code: u8);
+    /// Stops with `trap::OUT_OF_FUEL` if `VM::fuel` is zero, and otherwise takes one from it
+    fn consume_fuel(&mut self);
+    /// Adds one to `VM::counters[index]`
+    fn increment_counter(&mut self, index: usize);
+    /// Adds one to `VM::counters[taken]` if `reg` is non-zero, or to the counter after it
+    /// otherwise, leaving `reg` as it was
+    fn count_branch(&mut self, reg: Reg, taken: usize);
+    /// Moves the locals base on to a new frame's window, trapping if calls nest too deeply
+    fn enter_frame(&mut self);
+    /// Moves the locals base back to the window of the calling frame
+    fn leave_frame(&mut self);
+    /// Calls `target`, continuing after the call once the callee `ret`s. Returns the offset of
+    /// the return address, as recorded by the trap handler.
+    fn call(&mut self, target: BlockTarget) -> usize;
+    /// Pushes `src` onto the operand stack, trapping if it is full
+    fn push(&mut self, src: Reg);
+    /// Pops the top of the operand stack into `dst`, trapping if it is empty
+    fn pop(&mut self, dst: Reg);
+    fn jump(&mut self, target: BlockTarget);
+    fn jump_conditional(&mut self, reg: Reg, true_target: BlockTarget, false_target: BlockTarget);
+    /// Calls `func` with the VM registers from `r1` onwards as its arguments, leaving its
+    /// result in `dst`
+    fn call_into_rust(&mut self, dst: Reg, func: Func);
+    fn brk(&mut self);
+    fn ret(&mut self);
+    fn no_op(&mut self);
+
+    /// Every host function the emitted code calls into via `call_into_rust`
+    fn host_calls(&self) -> &[Func];
+
+    /// Emits veneers for branches that can't reach as far as the others, if any are at risk
+    /// of ending up out of range of their targets. Called between instructions, so anything
+    /// emitted must be branched over.
+    fn emit_veneers_if_needed(&mut self);
+
+    /// Records where every jump still waiting on a veneer is linked to, emitting veneers for
+    /// the ones too far from their target. Called once every block has been emitted, with the
+    /// offset of each block by its index.
+    fn resolve_pending_branches(&mut self, offsets: &[usize]) -> Result<(), LinkError>;
+
+    /// Every jump and call to a block emitted so far, by the offset `link_jump` patches it at
+    fn take_jumps(&mut self) -> Vec<(usize, BlockTarget)>;
+
+    /// Patches the jump recorded at `instr_offset` to branch to `target_offset`
+    fn link_jump(&mut self, target_offset: usize, instr_offset: usize) -> Result<(), LinkError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_runnable_targets_can_be_configured() {
+        assert_eq!(Target::named("aarch64"), Ok(Target::Aarch64));
+
+        let err = Target::named("riscv64").unwrap_err();
+        assert_eq!(err.variable, "JIT_TARGET");
+        assert_eq!(err.value, "riscv64");
+
+        match Target::host() {
+            Target::X86_64 => {
+                assert_eq!(Target::named("x86_64"), Ok(Target::X86_64));
+                assert_eq!(err.expected, "aarch64 or x86_64");
+            }
+            Target::Aarch64 => {
+                let err = Target::named("x86_64").unwrap_err();
+                assert_eq!(err.expected, "aarch64");
+            }
+        }
+    }
+}
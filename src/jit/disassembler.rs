@@ -0,0 +1,579 @@
+use std::fmt::{self, Display, Write};
+
+const CONDITIONS: [&str; 16] = [
+    "eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "al", "nv",
+];
+
+/// How a load/store pair instruction updates its base register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indexing {
+    Offset,
+    PreIndex,
+    PostIndex,
+}
+
+/// A single decoded AArch64 instruction, covering the subset of encodings `Arm64Writer` emits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    MovZ {
+        rd: u8,
+        imm16: u16,
+        shift: u8,
+    },
+    MovK {
+        rd: u8,
+        imm16: u16,
+        shift: u8,
+    },
+    MovReg {
+        rd: u8,
+        rm: u8,
+    },
+    Ldr {
+        rt: u8,
+        rn: u8,
+        offset: u32,
+    },
+    Str {
+        rt: u8,
+        rn: u8,
+        offset: u32,
+    },
+    Ldp {
+        rt: u8,
+        rt2: u8,
+        rn: u8,
+        offset: i32,
+        indexing: Indexing,
+    },
+    Stp {
+        rt: u8,
+        rt2: u8,
+        rn: u8,
+        offset: i32,
+        indexing: Indexing,
+    },
+    AddImm {
+        rd: u8,
+        rn: u8,
+        imm12: u16,
+    },
+    SubImm {
+        rd: u8,
+        rn: u8,
+        imm12: u16,
+    },
+    AddReg {
+        rd: u8,
+        rn: u8,
+        rm: u8,
+        lsl: u8,
+    },
+    SubReg {
+        rd: u8,
+        rn: u8,
+        rm: u8,
+        lsl: u8,
+    },
+    Madd {
+        rd: u8,
+        rn: u8,
+        rm: u8,
+        ra: u8,
+    },
+    Msub {
+        rd: u8,
+        rn: u8,
+        rm: u8,
+        ra: u8,
+    },
+    Udiv {
+        rd: u8,
+        rn: u8,
+        rm: u8,
+    },
+    CmpImm {
+        rn: u8,
+        imm12: u16,
+    },
+    CmpReg {
+        rn: u8,
+        rm: u8,
+        lsl: u8,
+    },
+    Cset {
+        rd: u8,
+        cond: u8,
+    },
+    B {
+        offset: i32,
+    },
+    Bl {
+        offset: i32,
+    },
+    BCond {
+        cond: u8,
+        offset: i32,
+    },
+    Blr {
+        rn: u8,
+    },
+    Ret {
+        rn: u8,
+    },
+    Brk {
+        imm16: u16,
+    },
+    Nop,
+    Unknown(u32),
+}
+
+impl Decoded {
+    pub fn decode(word: u32) -> Self {
+        let rd = (word & 0x1f) as u8;
+        let rn = ((word >> 5) & 0x1f) as u8;
+        let rm = ((word >> 16) & 0x1f) as u8;
+        let imm12 = ((word >> 10) & 0xfff) as u16;
+        let imm16 = ((word >> 5) & 0xffff) as u16;
+        let hw_shift = (((word >> 21) & 0b11) * 16) as u8;
+
+        match word {
+            0xd503201f => return Self::Nop,
+            _ if word & 0xfffffc1f == 0xd63f0000 => return Self::Blr { rn },
+            _ if word & 0xfffffc1f == 0xd65f0000 => return Self::Ret { rn },
+            _ if word & 0xffe0001f == 0xd4200000 => return Self::Brk { imm16 },
+            _ => {}
+        }
+
+        match word >> 23 {
+            0b110100101 => {
+                return Self::MovZ {
+                    rd,
+                    imm16,
+                    shift: hw_shift,
+                }
+            }
+            0b111100101 => {
+                return Self::MovK {
+                    rd,
+                    imm16,
+                    shift: hw_shift,
+                }
+            }
+            _ => {}
+        }
+
+        match word >> 22 {
+            0b1111100101 => {
+                return Self::Ldr {
+                    rt: rd,
+                    rn,
+                    offset: imm12 as u32 * 8,
+                }
+            }
+            0b1111100100 => {
+                return Self::Str {
+                    rt: rd,
+                    rn,
+                    offset: imm12 as u32 * 8,
+                }
+            }
+            0b1001000100 => return Self::AddImm { rd, rn, imm12 },
+            0b1101000100 => return Self::SubImm { rd, rn, imm12 },
+            0b1111000100 if rd == 31 => return Self::CmpImm { rn, imm12 },
+            op if op >> 3 == 0b1010100 && op & 0b110 != 0 => {
+                let indexing = match (op >> 1) & 0b11 {
+                    0b01 => Indexing::PostIndex,
+                    0b10 => Indexing::Offset,
+                    _ => Indexing::PreIndex,
+                };
+                let rt2 = ((word >> 10) & 0x1f) as u8;
+                let offset = sign_extend((word >> 15) & 0x7f, 7) * 8;
+                return match op & 1 {
+                    1 => Self::Ldp {
+                        rt: rd,
+                        rt2,
+                        rn,
+                        offset,
+                        indexing,
+                    },
+                    _ => Self::Stp {
+                        rt: rd,
+                        rt2,
+                        rn,
+                        offset,
+                        indexing,
+                    },
+                };
+            }
+            _ => {}
+        }
+
+        let imm6 = ((word >> 10) & 0x3f) as u8;
+        let ra = imm6 & 0x1f;
+        match word >> 21 {
+            0b10101010000 if imm6 == 0 && rn == 31 => return Self::MovReg { rd, rm },
+            0b10001011000 => {
+                return Self::AddReg {
+                    rd,
+                    rn,
+                    rm,
+                    lsl: imm6,
+                }
+            }
+            0b11001011000 => {
+                return Self::SubReg {
+                    rd,
+                    rn,
+                    rm,
+                    lsl: imm6,
+                }
+            }
+            0b10011011000 if imm6 >> 5 == 0 => return Self::Madd { rd, rn, rm, ra },
+            0b10011011000 => return Self::Msub { rd, rn, rm, ra },
+            0b10011010110 if imm6 == 0b000010 => return Self::Udiv { rd, rn, rm },
+            0b11101011000 if rd == 31 => return Self::CmpReg { rn, rm, lsl: imm6 },
+            0b10011010100 if (word >> 10) & 0b11 == 0b01 && rm == 31 && rn == 31 => {
+                let cond = ((word >> 12) & 0xf) as u8;
+                // CSET is an alias of CSINC with the inverted condition, which can't be AL or NV
+                if cond >> 1 != 0b111 {
+                    return Self::Cset { rd, cond: cond ^ 1 };
+                }
+            }
+            _ => {}
+        }
+
+        match word >> 26 {
+            0b000101 => {
+                return Self::B {
+                    offset: sign_extend(word & 0x3ffffff, 26) * 4,
+                }
+            }
+            0b100101 => {
+                return Self::Bl {
+                    offset: sign_extend(word & 0x3ffffff, 26) * 4,
+                }
+            }
+            _ => {}
+        }
+
+        if word >> 24 == 0b01010100 && word & 0b10000 == 0 {
+            return Self::BCond {
+                cond: (word & 0xf) as u8,
+                offset: sign_extend((word >> 5) & 0x7ffff, 19) * 4,
+            };
+        }
+
+        Self::Unknown(word)
+    }
+
+    /// The byte offset this instruction branches to, relative to its own address
+    pub fn branch_offset(&self) -> Option<i32> {
+        match self {
+            Self::B { offset } | Self::Bl { offset } | Self::BCond { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::MovZ { rd, imm16, shift } => {
+                write!(f, "movz {}, #{imm16:#x}", xreg(rd))?;
+                write_lsl(f, shift)
+            }
+            Self::MovK { rd, imm16, shift } => {
+                write!(f, "movk {}, #{imm16:#x}", xreg(rd))?;
+                write_lsl(f, shift)
+            }
+            Self::MovReg { rd, rm } => write!(f, "mov {}, {}", xreg(rd), xreg(rm)),
+            Self::Ldr { rt, rn, offset } => {
+                write!(f, "ldr {}, [{}, #{offset}]", xreg(rt), xreg_or_sp(rn))
+            }
+            Self::Str { rt, rn, offset } => {
+                write!(f, "str {}, [{}, #{offset}]", xreg(rt), xreg_or_sp(rn))
+            }
+            Self::AddImm { rd, rn, imm12 } => {
+                write!(f, "add {}, {}, #{imm12}", xreg_or_sp(rd), xreg_or_sp(rn))
+            }
+            Self::SubImm { rd, rn, imm12 } => {
+                write!(f, "sub {}, {}, #{imm12}", xreg_or_sp(rd), xreg_or_sp(rn))
+            }
+            Self::Ldp {
+                rt,
+                rt2,
+                rn,
+                offset,
+                indexing,
+            } => {
+                write!(f, "ldp {}, {}, ", xreg(rt), xreg(rt2))?;
+                write_pair_address(f, rn, offset, indexing)
+            }
+            Self::Stp {
+                rt,
+                rt2,
+                rn,
+                offset,
+                indexing,
+            } => {
+                write!(f, "stp {}, {}, ", xreg(rt), xreg(rt2))?;
+                write_pair_address(f, rn, offset, indexing)
+            }
+            Self::AddReg { rd, rn, rm, lsl } => {
+                write!(f, "add {}, {}, {}", xreg(rd), xreg(rn), xreg(rm))?;
+                write_lsl(f, lsl)
+            }
+            Self::SubReg { rd, rn, rm, lsl } => {
+                write!(f, "sub {}, {}, {}", xreg(rd), xreg(rn), xreg(rm))?;
+                write_lsl(f, lsl)
+            }
+            Self::Madd { rd, rn, rm, ra: 31 } => {
+                write!(f, "mul {}, {}, {}", xreg(rd), xreg(rn), xreg(rm))
+            }
+            Self::Madd { rd, rn, rm, ra } => {
+                let (rd, rn, rm, ra) = (xreg(rd), xreg(rn), xreg(rm), xreg(ra));
+                write!(f, "madd {rd}, {rn}, {rm}, {ra}")
+            }
+            Self::Msub { rd, rn, rm, ra } => {
+                let (rd, rn, rm, ra) = (xreg(rd), xreg(rn), xreg(rm), xreg(ra));
+                write!(f, "msub {rd}, {rn}, {rm}, {ra}")
+            }
+            Self::Udiv { rd, rn, rm } => {
+                write!(f, "udiv {}, {}, {}", xreg(rd), xreg(rn), xreg(rm))
+            }
+            Self::CmpImm { rn, imm12 } => write!(f, "cmp {}, #{imm12}", xreg_or_sp(rn)),
+            Self::CmpReg { rn, rm, lsl } => {
+                write!(f, "cmp {}, {}", xreg(rn), xreg(rm))?;
+                write_lsl(f, lsl)
+            }
+            Self::Cset { rd, cond } => {
+                write!(f, "cset {}, {}", xreg(rd), CONDITIONS[cond as usize])
+            }
+            Self::B { offset } => write!(f, "b {}", signed_hex(offset)),
+            Self::Bl { offset } => write!(f, "bl {}", signed_hex(offset)),
+            Self::BCond { cond, offset } => {
+                write!(f, "b.{} {}", CONDITIONS[cond as usize], signed_hex(offset))
+            }
+            Self::Blr { rn } => write!(f, "blr {}", xreg(rn)),
+            Self::Ret { rn: 30 } => write!(f, "ret"),
+            Self::Ret { rn } => write!(f, "ret {}", xreg(rn)),
+            Self::Brk { imm16 } => write!(f, "brk #{imm16:#x}"),
+            Self::Nop => write!(f, "nop"),
+            Self::Unknown(word) => write!(f, ".inst {word:#010x}  // <unknown>"),
+        }
+    }
+}
+
+/// Renders a listing of `code`, printing each label in `labels` ahead of the instruction at its
+/// byte offset and naming branch targets by label wherever one exists
+pub fn disassemble(code: &[u8], labels: &[(usize, String)]) -> String {
+    let mut listing = String::new();
+
+    for (i, chunk) in code.chunks(4).enumerate() {
+        let offset = i * 4;
+        for (_, label) in labels.iter().filter(|(x, _)| *x == offset) {
+            writeln!(&mut listing, "  {label}:").unwrap();
+        }
+
+        let Ok(word) = chunk.try_into().map(u32::from_le_bytes) else {
+            writeln!(
+                &mut listing,
+                "    {offset:04x}:  {chunk:02x?}  // <truncated>"
+            )
+            .unwrap();
+            break;
+        };
+
+        let decoded = Decoded::decode(word);
+        write!(&mut listing, "    {offset:04x}:  {word:08x}  {decoded}").unwrap();
+
+        if let Some(branch_offset) = decoded.branch_offset() {
+            let target = offset as i64 + branch_offset as i64;
+            match labels.iter().find(|(x, _)| *x as i64 == target) {
+                Some((_, label)) => write!(&mut listing, "  // -> {label}").unwrap(),
+                None => write!(&mut listing, "  // -> {target:04x}").unwrap(),
+            }
+        }
+        listing.push('\n');
+    }
+
+    listing
+}
+
+fn write_lsl(f: &mut fmt::Formatter<'_>, shift: u8) -> fmt::Result {
+    match shift {
+        0 => Ok(()),
+        shift => write!(f, ", lsl #{shift}"),
+    }
+}
+
+fn write_pair_address(
+    f: &mut fmt::Formatter<'_>,
+    rn: u8,
+    offset: i32,
+    indexing: Indexing,
+) -> fmt::Result {
+    let rn = xreg_or_sp(rn);
+    match indexing {
+        Indexing::Offset => write!(f, "[{rn}, #{offset}]"),
+        Indexing::PreIndex => write!(f, "[{rn}, #{offset}]!"),
+        Indexing::PostIndex => write!(f, "[{rn}], #{offset}"),
+    }
+}
+
+fn xreg(reg: u8) -> String {
+    match reg {
+        31 => "xzr".to_string(),
+        reg => format!("x{reg}"),
+    }
+}
+
+fn xreg_or_sp(reg: u8) -> String {
+    match reg {
+        31 => "sp".to_string(),
+        reg => format!("x{reg}"),
+    }
+}
+
+fn signed_hex(value: i32) -> String {
+    match value.is_negative() {
+        true => format!("-{:#x}", value.unsigned_abs()),
+        false => format!("+{value:#x}"),
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        jit::{
+            assembler::{Assembler, Reg},
+            backend::Backend,
+            Jit, Target,
+        },
+        testing::{self, SAMPLES},
+        vm::{BlockTarget, Comparison},
+    };
+
+    fn disassembled(word: u32) -> String {
+        Decoded::decode(word).to_string()
+    }
+
+    #[test]
+    fn decodes_every_emitted_form() {
+        let known = [
+            (0xd503201f, "nop"),
+            (0xd65f03c0, "ret"),
+            (0xd63f0080, "blr x4"),
+            (0xd4200000, "brk #0x0"),
+            (0xd2824684, "movz x4, #0x1234"),
+            (0xf2b7dde4, "movk x4, #0xbeef, lsl #16"),
+            (0xaa0503e4, "mov x4, x5"),
+            (0xf9400824, "ldr x4, [x1, #16]"),
+            (0xf9000444, "str x4, [x2, #8]"),
+            (0xa9bf7bfd, "stp x29, x30, [sp, #-16]!"),
+            (0xa8c17bfd, "ldp x29, x30, [sp], #16"),
+            (0xa90153f3, "stp x19, x20, [sp, #16]"),
+            (0x91000484, "add x4, x4, #1"),
+            (0xd10043ff, "sub sp, sp, #16"),
+            (0x8b050084, "add x4, x4, x5"),
+            (0x8b060c42, "add x2, x2, x6, lsl #3"),
+            (0xcb050084, "sub x4, x4, x5"),
+            (0x9b057c84, "mul x4, x4, x5"),
+            (0x9b0590c4, "msub x4, x6, x5, x4"),
+            (0x9ac50884, "udiv x4, x4, x5"),
+            (0xf100009f, "cmp x4, #0"),
+            (0xeb05009f, "cmp x4, x5"),
+            (0x9a9f17e4, "cset x4, eq"),
+            (0x9a9f27e4, "cset x4, lo"),
+            (0x9a9fa7e4, "cset x4, lt"),
+            (0x14000002, "b +0x8"),
+            (0x17ffffff, "b -0x4"),
+            (0x94000004, "bl +0x10"),
+            (0x54ffffc1, "b.ne -0x8"),
+            (0x5400080b, "b.lt +0x100"),
+            (0x54000023, "b.lo +0x4"),
+        ];
+        for (word, expected) in known {
+            assert_eq!(disassembled(word), expected, "decoding {word:#010x}");
+        }
+    }
+
+    #[test]
+    fn decodes_the_conditions_of_every_comparison() {
+        // `dst <cond> src` is set with CSET, and jumps on it branch to the false target with
+        // the inverse condition
+        let conditions = [
+            (Comparison::Equal, "eq", "ne"),
+            (Comparison::NotEqual, "ne", "eq"),
+            (Comparison::LessThan, "lo", "hs"),
+            (Comparison::LessOrEqual, "ls", "hi"),
+            (Comparison::GreaterThan, "hi", "ls"),
+            (Comparison::GreaterOrEqual, "hs", "lo"),
+            (Comparison::SignedLessThan, "lt", "ge"),
+            (Comparison::SignedLessOrEqual, "le", "gt"),
+            (Comparison::SignedGreaterThan, "gt", "le"),
+            (Comparison::SignedGreaterOrEqual, "ge", "lt"),
+        ];
+        for (comparison, cond, inverse) in conditions {
+            let mut assembler = Assembler::default();
+            assembler.compare(comparison, Reg::GPR0, Reg::GPR1);
+            assembler.jump_conditional(Reg::GPR0, BlockTarget(0), BlockTarget(1));
+
+            let words = assembler
+                .chunks(4)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()));
+            let listing: Vec<_> = words.take(3).map(disassembled).collect();
+            assert_eq!(
+                listing,
+                [
+                    "cmp x4, x5".to_string(),
+                    format!("cset x4, {cond}"),
+                    format!("b.{inverse} +0x0"),
+                ],
+                "{comparison:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_for_undecodable_words() {
+        assert_eq!(Decoded::decode(0), Decoded::Unknown(0));
+        assert_eq!(disassembled(0), ".inst 0x00000000  // <unknown>");
+        assert_eq!(disassembled(0xffffffff), ".inst 0xffffffff  // <unknown>");
+
+        let listing = disassemble(&[0x1f, 0x20, 0x03, 0xd5, 0xaa, 0xbb], &[]);
+        assert_eq!(
+            listing,
+            "    0000:  d503201f  nop\n    0004:  [aa, bb]  // <truncated>\n"
+        );
+    }
+
+    #[test]
+    fn listings_name_branch_targets_by_label() {
+        let (_, code) = SAMPLES[1];
+        let jit = Jit::compile_for(&testing::parse(code), Target::Aarch64).unwrap();
+        let listing = jit.listing();
+
+        for label in ["ENTRY", "LOOP_CHECK", "LOOP_BODY", "LOOP_END"] {
+            assert!(
+                listing.contains(&format!("\n  {label}:\n")),
+                "{label} in {listing}"
+            );
+        }
+        let jumps_to = |label| {
+            let annotation = format!("  // -> {label}");
+            listing
+                .lines()
+                .any(|x| x.starts_with("    ") && x.ends_with(&annotation))
+        };
+        assert!(jumps_to("LOOP_CHECK"));
+        assert!(jumps_to("LOOP_BODY"));
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt::Display;
+
+use crate::{host::Func, vm::VM};
+
+use super::disassembler::{Decoded, Indexing};
+
+const STACK_SIZE: usize = 64 * 1024;
+
+/// Return address handed to the emulated function, `RET`ing to it ends emulation
+const HOST_RETURN_ADDRESS: u64 = 0;
+
+/// Left in the caller-saved registers after a call into the host
+const CLOBBERED: u64 = 0xdead_beef_dead_beef;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    UndefinedInstruction { pc: usize, word: u32 },
+    MemoryAccess { pc: usize, addr: u64 },
+    BranchTarget { pc: usize, target: u64 },
+    Breakpoint { pc: usize, imm16: u16 },
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::UndefinedInstruction { pc, word } => {
+                write!(f, "undefined instruction {word:#010x} at {pc:#06x}")
+            }
+            Fault::MemoryAccess { pc, addr } => {
+                write!(f, "invalid memory access to {addr:#x} at {pc:#06x}")
+            }
+            Fault::BranchTarget { pc, target } => {
+                write!(f, "branch to unknown target {target:#x} at {pc:#06x}")
+            }
+            Fault::Breakpoint { pc, imm16 } => write!(f, "breakpoint #{imm16} hit at {pc:#06x}"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Flags {
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+}
+
+impl Flags {
+    fn from_sub(lhs: u64, rhs: u64) -> Self {
+        let result = lhs.wrapping_sub(rhs);
+        Self {
+            n: (result as i64) < 0,
+            z: result == 0,
+            c: lhs >= rhs,
+            v: ((lhs ^ rhs) & (lhs ^ result)) >> 63 != 0,
+        }
+    }
+
+    fn holds(&self, cond: u8) -> bool {
+        let holds = match cond >> 1 {
+            0b000 => self.z,
+            0b001 => self.c,
+            0b010 => self.n,
+            0b011 => self.v,
+            0b100 => self.c && !self.z,
+            0b101 => self.n == self.v,
+            0b110 => !self.z && self.n == self.v,
+            _ => return true,
+        };
+        // the lowest bit of the condition inverts its meaning
+        holds != (cond & 1 == 1)
+    }
+}
+
+/// A region of host memory the emulated code is allowed to load from, and store to if writable
+struct Region {
+    base: u64,
+    ptr: *mut u8,
+    len: usize,
+    writable: bool,
+}
+
+/// Interprets the AArch64 machine code produced by `Assembler`, following the same calling
+/// contract as the native executable. Loads and stores are confined to the VM's register,
+/// locals and operand stack arrays, its stack depth, its trap record, its result, its fuel, its
+/// return addresses, its profiling counters and a private machine stack. The rest of the VM
+/// struct can only be loaded from, and `BLR`s to host functions registered by `call_into_rust`
+/// are forwarded to the real Rust functions.
+pub struct Emulator<'a> {
+    code: &'a [u8],
+    host_calls: &'a [Func],
+    x: [u64; 31],
+    sp: u64,
+    pc: usize,
+    flags: Flags,
+}
+
+impl<'a> Emulator<'a> {
+    pub fn new(code: &'a [u8], host_calls: &'a [Func]) -> Self {
+        Self {
+            code,
+            host_calls,
+            x: [0; 31],
+            sp: 0,
+            pc: 0,
+            flags: Flags::default(),
+        }
+    }
+
+    /// Runs the code from offset `entry` until it returns to the host
+    pub fn run(mut self, vm: &mut VM, entry: usize) -> Result<(), Fault> {
+        let mut machine_stack = vec![0u8; STACK_SIZE];
+        let vm_ptr = vm as *mut VM;
+        let regions = [
+            region_of(&mut vm.registers),
+            region_of(&mut vm.locals),
+            region_of(&mut vm.stack),
+            region_of(std::slice::from_mut(&mut vm.stack_depth)),
+            region_of(std::slice::from_mut(&mut vm.trap)),
+            region_of(&mut vm.result),
+            region_of(std::slice::from_mut(&mut vm.fuel)),
+            region_of(&mut vm.return_addresses),
+            region_of(&mut vm.counters),
+            Region {
+                base: machine_stack.as_mut_ptr() as u64,
+                ptr: machine_stack.as_mut_ptr(),
+                len: machine_stack.len(),
+                writable: true,
+            },
+            Region {
+                base: vm_ptr as u64,
+                ptr: vm_ptr.cast(),
+                len: std::mem::size_of::<VM>(),
+                writable: false,
+            },
+        ];
+
+        self.x[0] = vm_ptr as u64;
+        self.x[1] = regions[0].base;
+        self.x[2] = regions[1].base + (8 * vm.entry_depth * vm.frame_size) as u64;
+        self.x[3] = regions[2].base;
+        self.x[30] = HOST_RETURN_ADDRESS;
+        self.pc = entry;
+        self.sp = (machine_stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+
+        loop {
+            let word = self
+                .code
+                .get(self.pc..self.pc + 4)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+                .ok_or(Fault::BranchTarget {
+                    pc: self.pc,
+                    target: self.code_address(self.pc),
+                })?;
+
+            let pc = self.pc;
+            self.pc += 4;
+
+            match Decoded::decode(word) {
+                Decoded::MovZ { rd, imm16, shift } => self.set_x(rd, (imm16 as u64) << shift),
+                Decoded::MovK { rd, imm16, shift } => {
+                    let mask = !(0xffff_u64 << shift);
+                    let value = (self.xzr(rd) & mask) | ((imm16 as u64) << shift);
+                    self.set_x(rd, value);
+                }
+                Decoded::MovReg { rd, rm } => self.set_x(rd, self.xzr(rm)),
+                Decoded::Ldr { rt, rn, offset } => {
+                    let addr = self.xsp(rn).wrapping_add(offset as u64);
+                    let value = load(&regions, addr).ok_or(Fault::MemoryAccess { pc, addr })?;
+                    self.set_x(rt, value);
+                }
+                Decoded::Str { rt, rn, offset } => {
+                    let addr = self.xsp(rn).wrapping_add(offset as u64);
+                    store(&regions, addr, self.xzr(rt)).ok_or(Fault::MemoryAccess { pc, addr })?;
+                }
+                Decoded::AddImm { rd, rn, imm12 } => {
+                    self.set_xsp(rd, self.xsp(rn).wrapping_add(imm12 as u64))
+                }
+                Decoded::SubImm { rd, rn, imm12 } => {
+                    self.set_xsp(rd, self.xsp(rn).wrapping_sub(imm12 as u64))
+                }
+                Decoded::Ldp {
+                    rt,
+                    rt2,
+                    rn,
+                    offset,
+                    indexing,
+                } => {
+                    let addr = self.pair_address(rn, offset, indexing);
+                    let fault = Fault::MemoryAccess { pc, addr };
+                    let first = load(&regions, addr).ok_or(fault)?;
+                    let second = load(&regions, addr + 8).ok_or(fault)?;
+                    self.write_back(rn, offset, indexing);
+                    self.set_x(rt, first);
+                    self.set_x(rt2, second);
+                }
+                Decoded::Stp {
+                    rt,
+                    rt2,
+                    rn,
+                    offset,
+                    indexing,
+                } => {
+                    let addr = self.pair_address(rn, offset, indexing);
+                    let fault = Fault::MemoryAccess { pc, addr };
+                    store(&regions, addr, self.xzr(rt)).ok_or(fault)?;
+                    store(&regions, addr + 8, self.xzr(rt2)).ok_or(fault)?;
+                    self.write_back(rn, offset, indexing);
+                }
+                Decoded::AddReg { rd, rn, rm, lsl } => {
+                    self.set_x(rd, self.xzr(rn).wrapping_add(self.xzr(rm) << lsl))
+                }
+                Decoded::SubReg { rd, rn, rm, lsl } => {
+                    self.set_x(rd, self.xzr(rn).wrapping_sub(self.xzr(rm) << lsl))
+                }
+                Decoded::Madd { rd, rn, rm, ra } => {
+                    let product = self.xzr(rn).wrapping_mul(self.xzr(rm));
+                    self.set_x(rd, self.xzr(ra).wrapping_add(product))
+                }
+                Decoded::Msub { rd, rn, rm, ra } => {
+                    let product = self.xzr(rn).wrapping_mul(self.xzr(rm));
+                    self.set_x(rd, self.xzr(ra).wrapping_sub(product))
+                }
+                Decoded::Udiv { rd, rn, rm } => {
+                    // UDIV yields zero rather than trapping when dividing by zero
+                    let quotient = self.xzr(rn).checked_div(self.xzr(rm)).unwrap_or(0);
+                    self.set_x(rd, quotient)
+                }
+                Decoded::CmpImm { rn, imm12 } => {
+                    self.flags = Flags::from_sub(self.xsp(rn), imm12 as u64)
+                }
+                Decoded::CmpReg { rn, rm, lsl } => {
+                    self.flags = Flags::from_sub(self.xzr(rn), self.xzr(rm) << lsl)
+                }
+                Decoded::Cset { rd, cond } => self.set_x(rd, self.flags.holds(cond) as u64),
+                Decoded::B { offset } => self.branch_relative(pc, offset),
+                Decoded::Bl { offset } => {
+                    self.x[30] = self.code_address(self.pc);
+                    self.branch_relative(pc, offset)
+                }
+                Decoded::BCond { cond, offset } => {
+                    if self.flags.holds(cond) {
+                        self.branch_relative(pc, offset)
+                    }
+                }
+                Decoded::Blr { rn } => {
+                    let target = self.xzr(rn);
+                    self.x[30] = self.code_address(self.pc);
+                    self.call(pc, target)?;
+                }
+                Decoded::Ret { rn } => match self.xzr(rn) {
+                    HOST_RETURN_ADDRESS => return Ok(()),
+                    target => self.branch_absolute(pc, target)?,
+                },
+                Decoded::Brk { imm16 } => return Err(Fault::Breakpoint { pc, imm16 }),
+                Decoded::Nop => {}
+                Decoded::Unknown(word) => return Err(Fault::UndefinedInstruction { pc, word }),
+            }
+        }
+    }
+
+    fn call(&mut self, pc: usize, target: u64) -> Result<(), Fault> {
+        let host_call = self.host_calls.iter().find(|func| func.address() == target);
+
+        match host_call {
+            Some(func) => {
+                self.x[0] = func.call([self.x[0], self.x[1], self.x[2]]);
+                // The callee may have overwritten any caller-saved register, so make sure code
+                // relying on them surviving the call faults rather than working by chance
+                self.x[1..=18].fill(CLOBBERED);
+                Ok(())
+            }
+            None => self.branch_absolute(pc, target),
+        }
+    }
+
+    /// The address a load/store pair accesses, which post-indexing leaves unadjusted
+    fn pair_address(&self, rn: u8, offset: i32, indexing: Indexing) -> u64 {
+        match indexing {
+            Indexing::PostIndex => self.xsp(rn),
+            Indexing::Offset | Indexing::PreIndex => self.xsp(rn).wrapping_add(offset as u64),
+        }
+    }
+
+    fn write_back(&mut self, rn: u8, offset: i32, indexing: Indexing) {
+        if indexing != Indexing::Offset {
+            self.set_xsp(rn, self.xsp(rn).wrapping_add(offset as u64));
+        }
+    }
+
+    fn branch_relative(&mut self, pc: usize, offset: i32) {
+        self.pc = (pc as i64 + offset as i64) as usize;
+    }
+
+    fn branch_absolute(&mut self, pc: usize, target: u64) -> Result<(), Fault> {
+        let base = self.code_address(0);
+        match target.checked_sub(base) {
+            Some(offset) if offset < self.code.len() as u64 => {
+                self.pc = offset as usize;
+                Ok(())
+            }
+            _ => Err(Fault::BranchTarget { pc, target }),
+        }
+    }
+
+    fn code_address(&self, offset: usize) -> u64 {
+        self.code.as_ptr() as u64 + offset as u64
+    }
+
+    /// Reads a register where encoding 31 is the zero register
+    fn xzr(&self, reg: u8) -> u64 {
+        self.x.get(reg as usize).copied().unwrap_or(0)
+    }
+
+    /// Reads a register where encoding 31 is the stack pointer
+    fn xsp(&self, reg: u8) -> u64 {
+        self.x.get(reg as usize).copied().unwrap_or(self.sp)
+    }
+
+    fn set_x(&mut self, reg: u8, value: u64) {
+        if let Some(x) = self.x.get_mut(reg as usize) {
+            *x = value;
+        }
+    }
+
+    fn set_xsp(&mut self, reg: u8, value: u64) {
+        match self.x.get_mut(reg as usize) {
+            Some(x) => *x = value,
+            None => self.sp = value,
+        }
+    }
+}
+
+fn region_of<T>(values: &mut [T]) -> Region {
+    Region {
+        base: values.as_mut_ptr() as u64,
+        ptr: values.as_mut_ptr().cast(),
+        len: std::mem::size_of_val(values),
+        writable: true,
+    }
+}
+
+fn find_region<'a>(mut regions: impl Iterator<Item = &'a Region>, addr: u64) -> Option<*mut u8> {
+    regions.find_map(|region| {
+        let offset = addr.checked_sub(region.base)? as usize;
+        // Safety: offset + 8 is within the region, which is borrowed for the whole emulation
+        (offset + 8 <= region.len).then(|| unsafe { region.ptr.add(offset) })
+    })
+}
+
+fn load(regions: &[Region], addr: u64) -> Option<u64> {
+    let ptr = find_region(regions.iter(), addr)?;
+    // Safety: find_region only returns pointers with 8 readable bytes
+    Some(unsafe { ptr.cast::<u64>().read_unaligned() })
+}
+
+fn store(regions: &[Region], addr: u64, value: u64) -> Option<()> {
+    let ptr = find_region(regions.iter().filter(|region| region.writable), addr)?;
+    // Safety: find_region only returns pointers with 8 writable bytes
+    unsafe { ptr.cast::<u64>().write_unaligned(value) };
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        differential,
+        error::TrapKind,
+        jit::{Executable, Jit, Options, Target},
+        testing::{self, SAMPLES},
+    };
+
+    use super::Fault;
+
+    #[test]
+    fn emulated_samples_run_like_the_interpreter() {
+        for (name, code) in SAMPLES {
+            let program = testing::parse(code);
+            for pin_registers in [false, true] {
+                let options = Options {
+                    pin_registers,
+                    ..Options::new(Target::Aarch64)
+                };
+                let report = differential::cross_check(&program, &testing::vm(), options)
+                    .unwrap_or_else(|err| panic!("failed to link {name}: {err}"));
+                assert!(
+                    report.is_match(),
+                    "{name} with pinned registers {pin_registers}: {report}"
+                );
+            }
+        }
+    }
+
+    // A breakpoint would stop the test process on a host that runs the code natively
+    #[cfg(not(target_arch = "aarch64"))]
+    #[test]
+    fn faults_are_reported_as_traps() {
+        let mut jit = Jit::new(Target::Aarch64);
+        jit.assembler.brk();
+
+        let mut vm = testing::vm();
+        let trap = Executable::new(jit).run(&mut vm).unwrap_err();
+        assert!(matches!(
+            trap.kind,
+            TrapKind::Fault(Fault::Breakpoint { pc: 0, .. })
+        ));
+        assert_eq!(trap.location, None);
+    }
+}
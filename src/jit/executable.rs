@@ -1,74 +1,343 @@
-use crate::vm::{Value, VM};
+use std::collections::HashMap;
 
-use super::Jit;
+use crate::{
+    error::{Location, Trap, TrapKind},
+    host::Func,
+    interpreter::{Frame, ProgramCounter},
+    vm::{BlockTarget, Value, VM},
+};
+
+use super::{
+    backend::{trap, Target},
+    emulator::Emulator,
+    memory::ExecutableMemory,
+    Jit,
+};
+
+enum Code {
+    Native(ExecutableMemory),
+    Emulated {
+        code: Vec<u8>,
+        host_calls: Vec<Func>,
+    },
+}
+
+/// How compiled code handed control back to the host, when it didn't trap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program ran `RET`, or returned from its entry block, along with the value `RET`
+    /// handed back if it had an operand
+    Exited(Option<Value>),
+    /// The VM ran out of fuel on entering a block, which the interpreter resumes from once the
+    /// VM is refuelled
+    OutOfFuel,
+    /// The program reached a block that wasn't compiled, or returned from the block compiled
+    /// code was resumed at, and the interpreter carries on from there
+    Suspended,
+}
 
 pub struct Executable {
-    code: mmap::MemoryMap,
+    code: Code,
+    sites: Vec<ProgramCounter>,
+    return_sites: HashMap<usize, ProgramCounter>,
+    entries: Vec<(BlockTarget, usize)>,
+    counters: usize,
+    /// The label of every block, by index, for reporting where traps happened
+    labels: Vec<String>,
 }
 
 impl Executable {
     pub fn new(jit: Jit) -> Self {
-        let buffer_size = jit.assembler.len(); // Replace with the actual size
-        let buffer_size = (buffer_size as f32 / mmap::MemoryMap::granularity() as f32).ceil()
-            as usize
-            * mmap::MemoryMap::granularity();
-
-        pub const MAP_PRIVATE: std::os::raw::c_int = 0x0002;
-        pub const MAP_ANON: std::os::raw::c_int = 0x1000;
-        pub const MAP_JIT: std::os::raw::c_int = 0x0800;
-
-        // Allocate executable memory
-        let executable_memory_opts = &[
-            mmap::MapOption::MapNonStandardFlags(MAP_ANON | MAP_PRIVATE | MAP_JIT),
-            mmap::MapOption::MapReadable,
-            mmap::MapOption::MapWritable,
-            mmap::MapOption::MapExecutable,
-        ];
+        if jit.target != Target::host() {
+            assert_eq!(
+                jit.target,
+                Target::Aarch64,
+                "only AArch64 code can be emulated on a foreign host"
+            );
 
-        eprintln!("allocating executable memory block...");
-        let executable_memory = mmap::MemoryMap::new(buffer_size, executable_memory_opts)
-            .expect("couldn't allocate executable memory block");
-
-        eprintln!("disabling write protections on thread...");
-        // Safety: this is safe to call here, no return/error value to handle
-        unsafe { libc::pthread_jit_write_protect_np(0) }
+            eprintln!(
+                "host can't run {:?} code natively, emulating instead",
+                jit.target
+            );
+            return Self {
+                code: Code::Emulated {
+                    code: jit.assembler.to_vec(),
+                    host_calls: jit.assembler.host_calls().to_vec(),
+                },
+                sites: jit.sites,
+                return_sites: jit.return_sites,
+                entries: jit.entries,
+                counters: jit.counters,
+                labels: jit.labels.into_iter().map(|(_, label)| label).collect(),
+            };
+        }
 
         eprintln!("copying bytecode to exec memory block...");
-        assert!(
-            executable_memory.len() > jit.assembler.len(),
-            "buffer overflow"
-        );
-        // Safety: the size of this buffer is greater than the jit.assembler.len()
-        unsafe { jit.copy_into(executable_memory.data()) }
-
-        eprintln!("re-enabling write protections on thread...");
-        // Safety: this is safe to call here, no return/error value to handle
-        unsafe { libc::pthread_jit_write_protect_np(1) }
+        let executable_memory = ExecutableMemory::new(&jit.assembler)
+            .expect("couldn't allocate executable memory block");
 
         eprintln!("copied bytecode to exec memory block");
 
         Self {
-            code: executable_memory,
+            code: Code::Native(executable_memory),
+            sites: jit.sites,
+            return_sites: jit.return_sites,
+            entries: jit.entries,
+            counters: jit.counters,
+            labels: jit.labels.into_iter().map(|(_, label)| label).collect(),
+        }
+    }
+
+    /// Runs the compiled program on `vm` from its entry block, returning the trap it stopped at
+    /// if it didn't exit. A VM that trapped or ran out of fuel is left as the interpreter would
+    /// have left it, part way through the program.
+    pub fn run(&self, vm: &mut VM) -> Result<ExitStatus, Trap> {
+        vm.rewind();
+        self.enter(vm, 0)
+    }
+
+    /// Whether compiled code can carry on with the program `vm` is part way through, which it
+    /// can at the start of any block compiled by `Jit::compile_region`
+    pub fn can_resume(&self, vm: &VM) -> bool {
+        self.entry_point(vm).is_some()
+    }
+
+    /// Carries on with the program `vm` is part way through in compiled code, until it exits or
+    /// hands back to the interpreter. Panics unless `can_resume` holds.
+    pub fn resume(&self, vm: &mut VM) -> Result<ExitStatus, Trap> {
+        let entry = self
+            .entry_point(vm)
+            .expect("compiled code can only resume at the start of a compiled block");
+        self.enter(vm, entry)
+    }
+
+    fn entry_point(&self, vm: &VM) -> Option<usize> {
+        let pc = vm.pc.filter(|pc| pc.instruction == 0)?;
+        let entry = self.entries.iter().find(|(block, _)| *block == pc.block);
+        entry.map(|(_, offset)| *offset)
+    }
+
+    /// Runs the code at offset `entry` within the frame of the interpreter's innermost call
+    fn enter(&self, vm: &mut VM, entry: usize) -> Result<ExitStatus, Trap> {
+        // Calls the interpreter was nested within stay on its call stack, beneath any that
+        // compiled code makes
+        let outer_frames = std::mem::take(&mut vm.frames);
+        vm.entry_depth = outer_frames.len();
+        vm.trap = 0;
+        vm.result = [0; 2];
+        // Profiling code counts into a VM that isn't profiling yet as if it had started to
+        if vm.counters.len() < self.counters {
+            vm.counters.resize(self.counters, 0);
+        }
+        vm.counters_base = vm.counters.as_mut_ptr() as usize;
+        // Unmetered code leaves the fuel alone, but metered code takes from unlimited fuel too
+        let fuel = vm.fuel();
+        let fault = match &self.code {
+            Code::Native(code) => {
+                Self::run_native(code, entry, vm);
+                None
+            }
+            Code::Emulated { code, host_calls } => {
+                Emulator::new(code, host_calls).run(vm, entry).err()
+            }
+        };
+        if fuel.is_none() {
+            vm.set_fuel(None);
+        }
+        if let Some(fault) = fault {
+            // There's no telling how far the program got, so it can only be started over
+            vm.rewind();
+            return Err(Trap {
+                kind: TrapKind::Fault(fault),
+                location: None,
+            });
+        }
+
+        let (code, site) = match std::mem::take(&mut vm.trap) {
+            0 => {
+                vm.rewind();
+                let [has_result, result] = vm.result;
+                return Ok(ExitStatus::Exited(
+                    (has_result != 0).then_some(Value(result)),
+                ));
+            }
+            trap => trap::decode(trap),
+        };
+        if code == trap::RETURNED {
+            // Returning from the entry frame ends the program, as it does in the interpreter
+            vm.frames = outer_frames;
+            return match vm.frames.pop() {
+                Some(frame) => {
+                    vm.pc = Some(frame.return_to);
+                    Ok(ExitStatus::Suspended)
+                }
+                None => {
+                    vm.rewind();
+                    Ok(ExitStatus::Exited(None))
+                }
+            };
+        }
+
+        self.restore_frames(vm, site, outer_frames);
+        match code {
+            trap::OUT_OF_FUEL => Ok(ExitStatus::OutOfFuel),
+            trap::SUSPEND => Ok(ExitStatus::Suspended),
+            code => Err(Trap {
+                kind: trap::kind(code),
+                location: vm.pc.map(|pc| Location {
+                    block: self.labels[pc.block.0].clone(),
+                    instruction: pc.instruction,
+                }),
+            }),
         }
     }
 
-    pub fn run(&self, vm: &mut VM) {
+    /// Rebuilds the interpreter's call stack from the return addresses recorded by the trap
+    /// handler on top of `outer_frames`, pointing the VM at the instruction that trapped at
+    /// `site`
+    fn restore_frames(&self, vm: &mut VM, site: usize, mut outer_frames: Vec<Frame>) {
+        let code_address = match &self.code {
+            Code::Native(code) => code.as_ptr() as u64,
+            Code::Emulated { code, .. } => code.as_ptr() as u64,
+        };
+
+        // The return address of the call into the entry block isn't a return site, so the
+        // calls made by the program end there
+        let return_sites = vm.return_addresses.iter().map_while(|address| {
+            let offset = address.checked_sub(code_address)?;
+            self.return_sites.get(&(offset as usize)).copied()
+        });
+        let mut frames: Vec<_> = return_sites.map(|return_to| Frame { return_to }).collect();
+        frames.reverse();
+        outer_frames.append(&mut frames);
+
+        vm.frames = outer_frames;
+        vm.pc = self.sites.get(site).copied();
+    }
+
+    fn run_native(code: &ExecutableMemory, entry: usize, vm: &mut VM) {
         eprintln!("transmuting ptr");
-        // Safety: this function will not return anything and arguments are place in x0,x1,x2... registers
-        let exec_fn: fn(*const VM, *mut Value, *mut Value) =
-            unsafe { std::mem::transmute(self.code.data()) };
+        // Safety: this function will not return anything and arguments are placed in the C ABI
+        // argument registers (x0-x3 on AArch64 and rdi,rsi,rdx,rcx on x86-64). Every entry
+        // point follows the same contract as the one at the start of the code.
+        let exec_fn: extern "C" fn(*mut VM, *mut Value, *mut Value, *mut Value) =
+            unsafe { std::mem::transmute(code.as_ptr().add(entry)) };
 
         eprintln!("running fn ptr");
 
-        // x0: VM& vm
-        // x1: Value* registers
-        // x2: Value* locals
+        // x0/rdi: VM& vm
+        // x1/rsi: Value* registers
+        // x2/rdx: Value* locals
+        // x3/rcx: Value* stack
         exec_fn(
-            vm as *const VM,
+            vm as *mut VM,
             vm.registers.as_mut_ptr(),
-            vm.locals.as_mut_ptr(),
+            vm.locals[vm.entry_depth * vm.frame_size..].as_mut_ptr(),
+            vm.stack.as_mut_ptr(),
         );
 
         eprintln!("finished running fn ptr");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interpreter::Status,
+        jit::Options,
+        testing::{self, SAMPLES},
+        vm::Comparison,
+    };
+
+    #[test]
+    fn out_of_fuel_runs_resume_in_the_interpreter() {
+        let (_, code) = SAMPLES[0];
+        let program = testing::parse(code);
+        let mut expected = testing::vm();
+        let expected_status = expected.run(&program);
+
+        for target in [Target::host(), Target::Aarch64] {
+            let options = Options {
+                fuel: true,
+                ..Options::new(target)
+            };
+            let executable = Executable::new(Jit::compile_with(&program, options).unwrap());
+            let mut vm = testing::vm();
+            vm.set_fuel(Some(5));
+            assert_eq!(executable.run(&mut vm), Ok(ExitStatus::OutOfFuel));
+            assert_eq!(vm.fuel(), Some(0));
+            assert!(
+                vm.call_depth() > 0,
+                "factorial should stop part way through a call"
+            );
+
+            vm.set_fuel(None);
+            let status = vm.run(&program);
+            let context = format!("factorial resumed from {target:?}");
+            assert_eq!(status, expected_status, "status differs for {context}");
+            testing::assert_same_state(&vm, &expected, &context);
+        }
+    }
+
+    #[test]
+    fn comparisons_tell_unsigned_from_signed_operands() {
+        // Each comparison against (u64::MAX, 1), where u64::MAX is -1 when signed, and
+        // against (1, u64::MAX)
+        let comparisons = [
+            ("EQUAL", Comparison::Equal, false, false),
+            ("NOT_EQUAL", Comparison::NotEqual, true, true),
+            ("LESS_THAN", Comparison::LessThan, false, true),
+            ("LESS_EQUAL", Comparison::LessOrEqual, false, true),
+            ("GREATER_THAN", Comparison::GreaterThan, true, false),
+            ("GREATER_EQUAL", Comparison::GreaterOrEqual, true, false),
+            ("LESS_THAN_SIGNED", Comparison::SignedLessThan, true, false),
+            (
+                "LESS_EQUAL_SIGNED",
+                Comparison::SignedLessOrEqual,
+                true,
+                false,
+            ),
+            (
+                "GREATER_THAN_SIGNED",
+                Comparison::SignedGreaterThan,
+                false,
+                true,
+            ),
+            (
+                "GREATER_EQUAL_SIGNED",
+                Comparison::SignedGreaterOrEqual,
+                false,
+                true,
+            ),
+        ];
+        let targets = [Target::Aarch64, Target::X86_64].into_iter();
+        let targets: Vec<_> = targets.filter(|x| x.is_runnable()).collect();
+
+        for (mnemonic, cond, max_vs_one, one_vs_max) in comparisons {
+            for (lhs, rhs, expected) in [(u64::MAX, 1, max_vs_one), (1, u64::MAX, one_vs_max)] {
+                let context = format!("{lhs} {mnemonic} {rhs}");
+                assert_eq!(cond.evaluate(lhs, rhs), expected, "{context}");
+
+                let program = testing::parse(&format!(
+                    "ENTRY:\n  LOAD_INT32 {lhs}\n  STORE_REG r1\n  LOAD_INT32 {rhs}\n  \
+                     {mnemonic} r1\n  STORE_REG r2\n  RET r2\n"
+                ));
+                let result = Some(Value(expected as u64));
+                assert_eq!(
+                    testing::vm().run(&program),
+                    Status::Exited(result),
+                    "interpreted {context}"
+                );
+                for &target in &targets {
+                    let executable = Executable::new(Jit::compile_for(&program, target).unwrap());
+                    assert_eq!(
+                        executable.run(&mut testing::vm()),
+                        Ok(ExitStatus::Exited(result)),
+                        "{context} compiled for {target:?}"
+                    );
+                }
+            }
+        }
+    }
+}
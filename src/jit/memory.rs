@@ -0,0 +1,129 @@
+use std::io;
+
+/// A page-aligned mapping holding a copy of some machine code, which is never writable and
+/// executable at the same time. The mapping is released when dropped.
+pub struct ExecutableMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecutableMemory {
+    /// Maps fresh read+write pages, copies the code across and then flips the pages to
+    /// read+execute with `mprotect`
+    #[cfg(not(target_os = "macos"))]
+    pub fn new(code: &[u8]) -> io::Result<Self> {
+        let memory = Self::map(code.len(), libc::PROT_READ | libc::PROT_WRITE, 0)?;
+
+        // Safety: the mapping is writable and at least code.len() bytes long
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), memory.ptr, code.len()) };
+
+        // Safety: ptr and len describe exactly the pages returned by mmap
+        let ret = unsafe {
+            libc::mprotect(
+                memory.ptr.cast(),
+                memory.len,
+                libc::PROT_READ | libc::PROT_EXEC,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        flush_icache(memory.ptr, code.len());
+        Ok(memory)
+    }
+
+    /// Maps the pages with `MAP_JIT` as required by the hardened runtime, toggling the calling
+    /// thread's write protection while the code is copied across
+    #[cfg(target_os = "macos")]
+    pub fn new(code: &[u8]) -> io::Result<Self> {
+        let memory = Self::map(
+            code.len(),
+            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+            libc::MAP_JIT,
+        )?;
+
+        extern "C" {
+            fn sys_icache_invalidate(start: *mut std::ffi::c_void, len: usize);
+        }
+
+        // Safety: MAP_JIT pages are only writable by this thread while write protection is off,
+        // and the mapping is at least code.len() bytes long
+        unsafe {
+            libc::pthread_jit_write_protect_np(0);
+            std::ptr::copy_nonoverlapping(code.as_ptr(), memory.ptr, code.len());
+            libc::pthread_jit_write_protect_np(1);
+            sys_icache_invalidate(memory.ptr.cast(), code.len());
+        }
+
+        Ok(memory)
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    fn map(len: usize, prot: libc::c_int, extra_flags: libc::c_int) -> io::Result<Self> {
+        // Safety: sysconf has no preconditions
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = len.max(1).div_ceil(page_size) * page_size;
+
+        // Safety: an anonymous private mapping at no fixed address doesn't alias any memory
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANON | extra_flags,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        // Safety: ptr and len describe exactly the pages returned by mmap
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// Makes freshly written code visible to instruction fetch. AArch64 doesn't keep its data and
+/// instruction caches coherent, so the written lines are cleaned to the point of unification
+/// and the matching instruction cache lines invalidated.
+#[cfg(all(target_arch = "aarch64", not(target_os = "macos")))]
+fn flush_icache(start: *const u8, len: usize) {
+    let ctr_el0: usize;
+    // Safety: CTR_EL0 is readable from EL0 on Linux
+    unsafe { std::arch::asm!("mrs {}, ctr_el0", out(reg) ctr_el0) };
+
+    let dcache_line = 4 << ((ctr_el0 >> 16) & 0xf);
+    let icache_line = 4 << (ctr_el0 & 0xf);
+    let start = start as usize;
+    let end = start + len;
+
+    // Safety: cache maintenance by address only requires the lines to be readable
+    unsafe {
+        for line in (start & !(dcache_line - 1)..end).step_by(dcache_line) {
+            std::arch::asm!("dc cvau, {}", in(reg) line);
+        }
+        std::arch::asm!("dsb ish");
+        for line in (start & !(icache_line - 1)..end).step_by(icache_line) {
+            std::arch::asm!("ic ivau, {}", in(reg) line);
+        }
+        std::arch::asm!("dsb ish", "isb");
+    }
+}
+
+/// x86-64 keeps its instruction cache coherent with stores, so there is nothing to flush
+#[cfg(all(not(target_arch = "aarch64"), not(target_os = "macos")))]
+fn flush_icache(_start: *const u8, _len: usize) {}
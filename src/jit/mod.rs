@@ -1,27 +1,201 @@
-use crate::{env_var_flag_is_set, vm::Instruction, vm::Program, vm::VMRegister};
+use std::collections::HashMap;
 
-use self::assembler::Reg;
+use crate::{
+    env_var_flag_is_set,
+    error::{ConfigError, LinkError},
+    interpreter::ProgramCounter,
+    profile::{COUNTERS_PER_BLOCK, ENTRIES, TAKEN},
+    vm::{BlockTarget, Instruction, Program, VMRegister},
+};
+
+use self::{
+    assembler::Reg,
+    backend::{trap, Backend, BinaryOp},
+};
+
+pub use self::{
+    backend::Target,
+    emulator::Fault,
+    executable::{Executable, ExitStatus},
+};
 
 mod assembler;
+mod backend;
+mod disassembler;
+mod emulator;
 mod executable;
+mod memory;
+mod x86_64;
+
+/// How a program is compiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub target: Target,
+    /// Keep the VM registers a program uses in host registers, writing them back to
+    /// `VM::registers` only when exiting or calling into the host
+    pub pin_registers: bool,
+    /// Take a unit of `VM::fuel` at the start of every block, stopping once there is none left
+    pub fuel: bool,
+    /// Count every block entered and which way every conditional jump goes in `VM::counters`,
+    /// for `VM::profile` to report
+    pub profile: bool,
+}
+
+impl Options {
+    pub fn new(target: Target) -> Self {
+        Self {
+            target,
+            pin_registers: false,
+            fuel: false,
+            profile: false,
+        }
+    }
+
+    /// The options chosen by the `JIT_TARGET` and `JIT_PIN_REGISTERS` environment variables
+    pub fn configured() -> Result<Self, ConfigError> {
+        Ok(Self {
+            target: Target::configured()?,
+            pin_registers: env_var_flag_is_set("JIT_PIN_REGISTERS"),
+            fuel: false,
+            profile: false,
+        })
+    }
+}
 
-#[derive(Default)]
 pub struct Jit {
-    assembler: assembler::Assembler,
+    target: Target,
+    assembler: Box<dyn Backend>,
+    labels: Vec<(usize, String)>,
+    /// The bytecode instruction each trap site was emitted for
+    sites: Vec<ProgramCounter>,
+    /// Where the interpreter carries on from after each call, by the offset of its return
+    /// address
+    return_sites: HashMap<usize, ProgramCounter>,
+    /// The blocks compiled code can be resumed at, along with the offset of their entry point
+    entries: Vec<(BlockTarget, usize)>,
+    /// How many of `VM::counters` the code counts into, which is none unless it profiles
+    counters: usize,
 }
 
 impl Jit {
-    pub fn compile(program: &Program) -> Self {
-        let mut jit = Jit::default();
-        let assembler = &mut jit.assembler;
+    pub fn new(target: Target) -> Self {
+        let assembler: Box<dyn Backend> = match target {
+            Target::Aarch64 => Box::<assembler::Assembler>::default(),
+            Target::X86_64 => Box::<x86_64::X64Assembler>::default(),
+        };
+        Self {
+            target,
+            assembler,
+            labels: vec![],
+            sites: vec![],
+            return_sites: HashMap::new(),
+            entries: vec![],
+            counters: 0,
+        }
+    }
+
+    /// Compiles `program` for the host architecture with the default options
+    pub fn compile(program: &Program) -> Result<Self, LinkError> {
+        Self::compile_for(program, Target::host())
+    }
+
+    pub fn compile_for(program: &Program, target: Target) -> Result<Self, LinkError> {
+        Self::compile_with(program, Options::new(target))
+    }
+
+    pub fn compile_with(program: &Program, options: Options) -> Result<Self, LinkError> {
+        Self::compile_blocks(program, options, None)
+    }
+
+    /// Compiles only the blocks of `program` at the indices in `region`, giving each an entry
+    /// point that `Executable::resume` can carry on from. Compiled code that enters any other
+    /// block suspends there, for the interpreter to take over.
+    pub fn compile_region(
+        program: &Program,
+        options: Options,
+        region: &[usize],
+    ) -> Result<Self, LinkError> {
+        Self::compile_blocks(program, options, Some(region))
+    }
+
+    fn compile_blocks(
+        program: &Program,
+        options: Options,
+        region: Option<&[usize]>,
+    ) -> Result<Self, LinkError> {
+        let mut jit = Jit::new(options.target);
+        if options.profile {
+            jit.counters = program.blocks.len() * COUNTERS_PER_BLOCK;
+        }
+        let assembler = jit.assembler.as_mut();
+        let mut offsets = vec![0; program.blocks.len()];
+
+        if options.pin_registers {
+            assembler.pin_vm_registers(registers_used(program));
+        }
 
-        for block in program.blocks.iter() {
-            block.borrow_mut().offset = assembler.len();
+        // The entry block is called like any other, so that returning from it exits
+        match program.blocks.is_empty() {
+            false => {
+                assembler.prologue();
+                assembler.call(BlockTarget(0));
+                assembler.epilogue();
+                assembler.trap_handler();
+            }
+            true => assembler.ret(),
+        }
+
+        // Entry points call their block with the calls the interpreter made still counted, and
+        // tell the host when it returns rather than treating it as the end of the program
+        for &index in region.unwrap_or_default() {
+            let block = BlockTarget(index);
+            jit.entries.push((block, assembler.len()));
+            assembler.prologue();
+            assembler.reserve_frames();
+            assembler.call(block);
+            assembler.store_trap(trap::RETURNED);
+            assembler.epilogue();
+        }
 
-            for instruction in &block.borrow().instructions {
-                let instruction = instruction.borrow().clone();
+        for (index, block) in program.blocks.iter().enumerate() {
+            assembler.begin_block();
+            offsets[index] = assembler.len();
 
-                match instruction {
+            let this_block = BlockTarget(index);
+            if region.is_some_and(|region| !region.contains(&index)) {
+                assembler.set_trap_site(jit.sites.len());
+                jit.sites.push(ProgramCounter {
+                    block: this_block,
+                    instruction: 0,
+                });
+                assembler.trap(trap::SUSPEND);
+                assembler.emit_veneers_if_needed();
+                continue;
+            }
+
+            // Running out of fuel stops before the block's first instruction
+            if options.fuel {
+                assembler.set_trap_site(jit.sites.len());
+                jit.sites.push(ProgramCounter {
+                    block: this_block,
+                    instruction: 0,
+                });
+                assembler.consume_fuel();
+            }
+            let counters = index * COUNTERS_PER_BLOCK;
+            if options.profile {
+                assembler.increment_counter(counters + ENTRIES);
+            }
+
+            for (i, instruction) in block.instructions.iter().enumerate() {
+                // Each instruction is its own trap site, so traps can be traced back to it
+                assembler.set_trap_site(jit.sites.len());
+                jit.sites.push(ProgramCounter {
+                    block: this_block,
+                    instruction: i,
+                });
+
+                match *instruction {
                     Instruction::LoadImmediate { value } => {
                         assembler.load_immediate64(Reg::GPR0, value.0);
                         assembler.store_vm_register(VMRegister(0), Reg::GPR0);
@@ -47,96 +221,136 @@ impl Jit {
                         assembler.increment(Reg::GPR0);
                         assembler.store_vm_register(VMRegister(0), Reg::GPR0);
                     }
-                    Instruction::LessThan { lhs } => {
+                    Instruction::Add { rhs } => binary_op(assembler, BinaryOp::Add, rhs),
+                    Instruction::Subtract { rhs } => binary_op(assembler, BinaryOp::Sub, rhs),
+                    Instruction::Multiply { rhs } => binary_op(assembler, BinaryOp::Mul, rhs),
+                    Instruction::Divide { rhs } => binary_op(assembler, BinaryOp::Div, rhs),
+                    Instruction::Modulo { rhs } => binary_op(assembler, BinaryOp::Rem, rhs),
+                    Instruction::Compare { cond, lhs } => {
                         assembler.load_vm_register(Reg::GPR0, lhs);
                         assembler.load_vm_register(Reg::GPR1, VMRegister(0));
 
-                        assembler.less_than(Reg::GPR0, Reg::GPR1);
+                        assembler.compare(cond, Reg::GPR0, Reg::GPR1);
                         assembler.store_vm_register(VMRegister(0), Reg::GPR0);
                     }
                     Instruction::Breakpoint => {
                         assembler.brk();
                     }
-                    Instruction::Exit => {
-                        assembler.ret();
+                    Instruction::Exit { result } => {
+                        if let Some(result) = result {
+                            assembler.load_vm_register(Reg::GPR0, result);
+                            assembler.store_result(Reg::GPR0);
+                        }
+                        assembler.epilogue();
+                    }
+                    Instruction::Abort => {
+                        assembler.trap(trap::ABORT);
                     }
                     Instruction::Jump { target } => {
-                        assembler.jump(&target);
+                        assembler.jump(target);
                     }
                     Instruction::JumpConditional {
                         true_target,
                         false_target,
                     } => {
                         assembler.load_vm_register(Reg::GPR0, VMRegister(0));
-                        assembler.jump_conditional(Reg::GPR0, &true_target, &false_target);
+                        if options.profile {
+                            assembler.count_branch(Reg::GPR0, counters + TAKEN);
+                        }
+                        assembler.jump_conditional(Reg::GPR0, true_target, false_target);
+                    }
+                    Instruction::Call { target } => {
+                        assembler.enter_frame();
+                        let return_address = assembler.call(target);
+                        assembler.leave_frame();
+
+                        jit.return_sites.insert(
+                            return_address,
+                            ProgramCounter {
+                                block: this_block,
+                                instruction: i + 1,
+                            },
+                        );
+                    }
+                    Instruction::Return => {
+                        assembler.ret();
+                    }
+                    Instruction::Push => {
+                        assembler.load_vm_register(Reg::GPR0, VMRegister(0));
+                        assembler.push(Reg::GPR0);
+                    }
+                    Instruction::Pop => {
+                        assembler.pop(Reg::GPR0);
+                        assembler.store_vm_register(VMRegister(0), Reg::GPR0);
+                    }
+                    Instruction::Duplicate => {
+                        assembler.pop(Reg::GPR0);
+                        assembler.push(Reg::GPR0);
+                        assembler.push(Reg::GPR0);
+                    }
+                    Instruction::Swap => {
+                        assembler.pop(Reg::GPR0);
+                        assembler.pop(Reg::GPR1);
+                        assembler.push(Reg::GPR0);
+                        assembler.push(Reg::GPR1);
+                    }
+                    Instruction::CallNative { ref function } => {
+                        assembler.call_into_rust(Reg::GPR0, function.func);
+                        assembler.store_vm_register(VMRegister(0), Reg::GPR0);
                     }
                 }
+                assembler.emit_veneers_if_needed();
             }
         }
+        assembler.resolve_pending_branches(&offsets)?;
+        for (jump, target) in assembler.take_jumps() {
+            assembler.link_jump(offsets[target.0], jump)?;
+        }
 
-        for block in &program.blocks {
-            let block_offset = block.borrow().offset;
-            for jump in block.borrow().jumps_to_here.iter().copied() {
-                jit.link_and_rewrite(block_offset, jump);
-            }
+        let labels = program.blocks.iter().map(|block| block.label.clone());
+        jit.labels = offsets.into_iter().zip(labels).collect();
+        Ok(jit)
+    }
+
+    pub fn dump(&self) {
+        eprintln!("exec dump: ");
+        eprintln!("{}", self.listing());
+        eprintln!();
+
+        self.bytecode_to_file();
+    }
+
+    pub fn into_exec(self) -> executable::Executable {
+        if env_var_flag_is_set("DRY_RUN") {
+            eprintln!("Dry run mode is enabled, quitting..");
+            std::process::exit(0);
         }
-        jit
+
+        executable::Executable::new(self)
     }
 
-    fn link_and_rewrite(&mut self, target_offset: usize, instr_offset: usize) {
-        const OP_JMP: u8 = 0b000101;
-        const OP_JEQ: u8 = 0b010101;
-
-        let jump_instr = &self.assembler[instr_offset..instr_offset + 4];
-        let op_code = jump_instr[3] >> 2;
-
-        let byte_offset = target_offset as i16 - instr_offset as i16;
-        let offset = byte_offset / 4;
-
-        let value = match op_code {
-            OP_JMP => assembler::BitwiseWriter::write(|idx| match idx {
-                0 => Some(assembler::BitIndex {
-                    value: op_code as usize,
-                    bits: 6,
-                }),
-                1 => Some(assembler::BitIndex {
-                    value: sign_extend_upper_bits(offset, 10),
-                    bits: 10,
-                }),
-                2 => Some(assembler::BitIndex {
-                    value: sign_extend(offset, 16),
-                    bits: 16,
-                }),
-                _ => None,
-            }),
-            OP_JEQ => assembler::BitwiseWriter::write(|idx| match idx {
-                0 => Some(assembler::BitIndex {
-                    value: 0b01010100,
-                    bits: 8,
-                }),
-                1 => Some(assembler::BitIndex {
-                    value: sign_extend_upper_bits(offset, 3),
-                    bits: 3,
-                }),
-                2 => Some(assembler::BitIndex {
-                    value: sign_extend(offset, 16),
-                    bits: 16,
-                }),
-                3 => Some(assembler::BitIndex { value: 0, bits: 5 }),
-                _ => None,
-            }),
-            b => todo!("handle additional jump instructions 0b{b:06x}"),
-        };
+    pub fn dummy(target: Target) -> Self {
+        let mut jit = Self::new(target);
+        jit.assembler.no_op();
+        jit.assembler.no_op();
+        jit.assembler.no_op();
+        jit.assembler.ret();
+        jit
+    }
 
-        self.assembler.rewrite_instr32(instr_offset, value.unwrap());
+    /// The compiled code as a disassembly where the target supports one, or as hex otherwise
+    fn listing(&self) -> String {
+        match self.target {
+            Target::Aarch64 => disassembler::disassemble(&self.assembler, &self.labels),
+            Target::X86_64 => self.hex_dump(),
+        }
     }
 
-    pub fn dump(&self) {
+    fn hex_dump(&self) -> String {
         let len = self.assembler.len();
         let init = String::with_capacity(len * 4);
 
-        let hex = self
-            .assembler
+        self.assembler
             .chunks(2)
             .enumerate()
             .fold(init, |mut s, (i, x)| {
@@ -154,36 +368,6 @@ impl Jit {
                 }
                 s
             })
-            .to_string();
-
-        eprintln!("exec dump: ");
-        eprintln!("{hex}");
-        eprintln!("");
-
-        self.bytecode_to_file();
-    }
-
-    pub fn into_exec(self) -> executable::Executable {
-        if env_var_flag_is_set("DRY_RUN") {
-            eprintln!("Dry run mode is enabled, quitting..");
-            std::process::exit(0);
-        }
-
-        executable::Executable::new(self)
-    }
-
-    pub fn dummy() -> Self {
-        let mut jit = Self::default();
-        jit.assembler.no_op();
-        jit.assembler.no_op();
-        jit.assembler.no_op();
-        jit.assembler.ret();
-        jit
-    }
-
-    /// Safety: must ensure the dst buffer is at least as large as self.assembler.len()
-    pub unsafe fn copy_into(&self, dst: *mut u8) {
-        std::ptr::copy(self.assembler.as_ptr(), dst, self.assembler.len())
     }
 
     fn bytecode_to_file(&self) {
@@ -196,19 +380,29 @@ impl Jit {
     }
 }
 
-fn sign_extend_upper_bits(value: i16, bits: usize) -> usize {
-    if value.is_negative() {
-        (1 << bits) - 1
-    } else {
-        0
-    }
+/// One more than the highest VM register `program` reads or writes, so that pinning registers
+/// never touches one beyond the end of `VM::registers` that the program wouldn't
+fn registers_used(program: &Program) -> usize {
+    let instructions = program.blocks.iter().flat_map(|block| &block.instructions);
+
+    let highest = instructions.map(|instruction| match *instruction {
+        Instruction::Load { reg } | Instruction::Store { reg } => reg.0,
+        Instruction::Add { rhs }
+        | Instruction::Subtract { rhs }
+        | Instruction::Multiply { rhs }
+        | Instruction::Divide { rhs }
+        | Instruction::Modulo { rhs } => rhs.0,
+        Instruction::Compare { lhs, .. } => lhs.0,
+        Instruction::Exit { result } => result.map_or(0, |reg| reg.0),
+        Instruction::CallNative { ref function } => function.func.arity(),
+        _ => 0,
+    });
+    highest.max().map_or(0, |highest| highest + 1)
 }
 
-fn sign_extend(value: i16, bits: usize) -> usize {
-    if value.is_negative() {
-        let max_bit_value: i64 = 1 << bits;
-        (max_bit_value + value as i64) as usize
-    } else {
-        value as usize
-    }
+fn binary_op(assembler: &mut dyn Backend, op: BinaryOp, rhs: VMRegister) {
+    assembler.load_vm_register(Reg::GPR0, VMRegister(0));
+    assembler.load_vm_register(Reg::GPR1, rhs);
+    assembler.binary_op(op, Reg::GPR0, Reg::GPR1);
+    assembler.store_vm_register(VMRegister(0), Reg::GPR0);
 }
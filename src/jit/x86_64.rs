@@ -0,0 +1,698 @@
+use crate::{
+    error::LinkError,
+    host::Func,
+    vm::{BlockTarget, Comparison, VMLocal, VMRegister, MAX_CALL_DEPTH, MAX_STACK_DEPTH, VM},
+};
+
+use super::{
+    assembler::Reg,
+    backend::{trap, Backend, BinaryOp},
+};
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSP: u8 = 4;
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R8: u8 = 8;
+const R9: u8 = 9;
+const R10: u8 = 10;
+const R11: u8 = 11;
+
+const COND_B: u8 = 0x2;
+const COND_AE: u8 = 0x3;
+const COND_EQ: u8 = 0x4;
+const COND_NE: u8 = 0x5;
+const COND_BE: u8 = 0x6;
+const COND_A: u8 = 0x7;
+const COND_LT: u8 = 0xc;
+const COND_GE: u8 = 0xd;
+const COND_LE: u8 = 0xe;
+const COND_GT: u8 = 0xf;
+
+/// Maps the logical `Reg`s onto the x86-64 register file, following the System V ABI so that
+/// the VM pointer, register array and locals array arrive in rdi, rsi and rdx respectively. The
+/// operand stack arrives in rcx, which the prologue moves to r9 as rcx is also `GPR1`.
+fn encode(reg: Reg) -> u8 {
+    match reg {
+        Reg::GPR0 => RAX,
+        Reg::GPR1 => RCX,
+        Reg::GPR2 => R8,
+        Reg::VmStructBase => RDI,
+        Reg::RegisterArrayBase => RSI,
+        Reg::LocalsArrayBase => RDX,
+        Reg::StackArrayBase => R9,
+        Reg::EntryFrame => RBX,
+        Reg::StackDepth => R10,
+        Reg::FP => RBP,
+        Reg::SP => RSP,
+        Reg::RET => panic!("x86-64 has no link register, return addresses live on the stack"),
+        Reg::Pinned0
+        | Reg::Pinned1
+        | Reg::Pinned2
+        | Reg::Pinned3
+        | Reg::Pinned4
+        | Reg::Pinned5
+        | Reg::Pinned6
+        | Reg::Pinned7 => panic!("x86-64 doesn't pin VM registers"),
+    }
+}
+
+/// Maps a comparison onto the condition code tested by `SETcc` after `CMP lhs, rhs`
+fn encode_cond(cond: Comparison) -> u8 {
+    match cond {
+        Comparison::Equal => COND_EQ,
+        Comparison::NotEqual => COND_NE,
+        Comparison::LessThan => COND_B,
+        Comparison::LessOrEqual => COND_BE,
+        Comparison::GreaterThan => COND_A,
+        Comparison::GreaterOrEqual => COND_AE,
+        Comparison::SignedLessThan => COND_LT,
+        Comparison::SignedLessOrEqual => COND_LE,
+        Comparison::SignedGreaterThan => COND_GT,
+        Comparison::SignedGreaterOrEqual => COND_GE,
+    }
+}
+
+#[derive(Default)]
+pub struct X64Assembler {
+    output: Vec<u8>,
+    host_calls: Vec<Func>,
+    /// Jumps and calls to blocks, which are linked once every block has been emitted
+    jumps: Vec<(usize, BlockTarget)>,
+    trap_handler: Option<usize>,
+    trap_site: usize,
+}
+
+impl Backend for X64Assembler {
+    fn load_immediate64(&mut self, dst: Reg, imm: u64) {
+        self.writer().emit_mov_imm(encode(dst), imm);
+    }
+
+    fn store_vm_register(&mut self, dst: VMRegister, src: Reg) {
+        self.writer()
+            .emit_store(encode(Reg::RegisterArrayBase), dst.0, encode(src));
+    }
+
+    fn load_vm_register(&mut self, dst: Reg, src: VMRegister) {
+        self.writer()
+            .emit_load(encode(dst), encode(Reg::RegisterArrayBase), src.0);
+    }
+
+    fn store_vm_local(&mut self, dst: VMLocal, src: Reg) {
+        self.writer()
+            .emit_store(encode(Reg::LocalsArrayBase), dst.0, encode(src));
+    }
+
+    fn load_vm_local(&mut self, dst: Reg, src: VMLocal) {
+        self.writer()
+            .emit_load(encode(dst), encode(Reg::LocalsArrayBase), src.0);
+    }
+
+    fn increment(&mut self, dst: Reg) {
+        // add dst, 1
+        self.writer().emit_add_imm8(encode(dst), 1);
+    }
+
+    fn binary_op(&mut self, op: BinaryOp, dst: Reg, src: Reg) {
+        let (dst, src) = (encode(dst), encode(src));
+        match op {
+            BinaryOp::Add => self.writer().emit_alu_reg(0x01, dst, src),
+            BinaryOp::Sub => self.writer().emit_alu_reg(0x29, dst, src),
+            BinaryOp::Mul => self.writer().emit_imul(dst, src),
+            BinaryOp::Div | BinaryOp::Rem => {
+                // DIV takes its dividend from rdx:rax and leaves the remainder in rdx, so the
+                // locals base held in rdx is saved around it
+                assert!(
+                    dst == RAX && src != RDX,
+                    "x86-64 division needs the dividend in rax"
+                );
+
+                // DIV faults on a zero divisor, so trap before it
+                self.writer().emit_test(src);
+                self.trap_unless(COND_NE, trap::DIVISION_BY_ZERO);
+
+                self.writer().emit_push(RDX);
+                self.writer().emit_alu_reg32(0x31, RDX, RDX);
+                self.writer().emit_div(src);
+                if op == BinaryOp::Rem {
+                    self.writer().emit_mov_reg(RAX, RDX);
+                }
+                self.writer().emit_pop(RDX);
+            }
+        }
+    }
+
+    fn compare(&mut self, cond: Comparison, dst: Reg, src: Reg) {
+        // Set the flags from dst - src
+        self.writer().emit_cmp(encode(dst), encode(src));
+
+        // Set dst to 1 if dst <cond> src, else set it to 0
+        self.writer().emit_setcc(encode_cond(cond), encode(dst));
+        self.writer().emit_movzx_byte(encode(dst));
+    }
+
+    fn begin_block(&mut self) {
+        // Nothing is carried between instructions here, so there's nothing to forget
+    }
+
+    fn pin_vm_registers(&mut self, _count: usize) {
+        // Only the AArch64 backend pins VM registers, here they always stay in memory
+    }
+
+    fn prologue(&mut self) {
+        // Push a frame record, saving the callee-saved register used to unwind back here
+        self.writer().emit_push(RBP);
+        self.writer().emit_mov_reg(RBP, RSP);
+        self.writer().emit_push(RBX);
+        self.writer().emit_mov_reg(RBX, RSP);
+
+        assert_eq!(VM::STACK_DEPTH_OFFSET % 8, 0);
+        self.writer().emit_mov_reg(R9, RCX);
+        self.writer()
+            .emit_load(R10, RDI, VM::STACK_DEPTH_OFFSET / 8);
+    }
+
+    fn epilogue(&mut self) {
+        self.writer()
+            .emit_store(RDI, VM::STACK_DEPTH_OFFSET / 8, R10);
+
+        // Drop every frame pushed since the prologue, then restore what it saved
+        self.writer().emit_mov_reg(RSP, RBX);
+        self.writer().emit_pop(RBX);
+        self.writer().emit_pop(RBP);
+        self.writer().emit_ret();
+    }
+
+    fn reserve_frames(&mut self) {
+        assert_eq!(VM::ENTRY_DEPTH_OFFSET % 8, 0);
+        self.writer()
+            .emit_load(RAX, RDI, VM::ENTRY_DEPTH_OFFSET / 8);
+        self.writer().emit_shl_imm8(RAX, 4);
+        self.writer().emit_alu_reg(0x29, RSP, RAX);
+    }
+
+    fn store_result(&mut self, src: Reg) {
+        assert_ne!(src, Reg::GPR2);
+        assert_eq!(VM::RESULT_OFFSET % 8, 0);
+        let offset = VM::RESULT_OFFSET / 8;
+        self.writer().emit_mov_imm(R8, 1);
+        self.writer().emit_store(RDI, offset, R8);
+        self.writer().emit_store(RDI, offset + 1, encode(src));
+    }
+
+    fn trap_handler(&mut self) {
+        // Traps jump here with the value to record in r8
+        self.trap_handler = Some(self.len());
+        assert_eq!(VM::TRAP_OFFSET % 8, 0);
+        self.writer().emit_store(RDI, VM::TRAP_OFFSET / 8, R8);
+
+        // Every call the trap is nested within pushed its return address above the rbp it
+        // saved between rsp and the entry frame, so walk them from the innermost
+        self.writer().emit_mov_reg(RAX, RSP);
+        self.writer()
+            .emit_mov_imm(RCX, VM::RETURN_ADDRESSES_OFFSET as u64);
+        self.writer().emit_alu_reg(0x01, RCX, RDI);
+        let next_frame = self.len();
+        self.writer().emit_cmp(RAX, RBX);
+        let done = self.writer().emit_jcc_rel8(COND_AE);
+        self.writer().emit_load(R8, RAX, 0);
+        self.writer().emit_store(RCX, 0, R8);
+        self.writer().emit_add_imm8(RCX, 8);
+        self.writer().emit_add_imm8(RAX, 16);
+        self.writer().emit_jmp_rel32(0);
+        self.link_jump(next_frame, self.len() - 4)
+            .expect("the loop is smaller than the range of JMP");
+        self.bind_rel8(done);
+
+        self.epilogue();
+    }
+
+    fn set_trap_site(&mut self, site: usize) {
+        self.trap_site = site;
+    }
+
+    fn trap(&mut self, code: u8) {
+        let handler = self
+            .trap_handler
+            .expect("the trap handler should be emitted before code that can trap");
+        let value = trap::encode(code, self.trap_site);
+        self.writer().emit_mov_imm(R8, value);
+
+        self.writer().emit_jmp_rel32(0);
+        self.link_jump(handler, self.len() - 4)
+            .expect("the trap handler should be in range of JMP");
+    }
+
+    fn store_trap(&mut self, code: u8) {
+        let value = trap::encode(code, self.trap_site);
+        self.writer().emit_mov_imm(R8, value);
+        self.writer().emit_store(RDI, VM::TRAP_OFFSET / 8, R8);
+    }
+
+    fn consume_fuel(&mut self) {
+        assert_eq!(VM::FUEL_OFFSET % 8, 0);
+        let offset = VM::FUEL_OFFSET / 8;
+        self.writer().emit_load(RAX, RDI, offset);
+        self.writer().emit_test(RAX);
+        self.trap_unless(COND_NE, trap::OUT_OF_FUEL);
+
+        self.writer().emit_add_imm8(RAX, -1);
+        self.writer().emit_store(RDI, offset, RAX);
+    }
+
+    fn increment_counter(&mut self, index: usize) {
+        self.writer().emit_mov_imm(RCX, index as u64);
+        self.increment_counter_at_rcx();
+    }
+
+    fn count_branch(&mut self, reg: Reg, taken: usize) {
+        let reg = encode(reg);
+        assert!(reg != RCX && reg != R8);
+        // The not taken counter follows the taken one, so index it by adding whether reg is zero
+        self.writer().emit_test(reg);
+        self.writer().emit_setcc(COND_EQ, RCX);
+        self.writer().emit_movzx_byte(RCX);
+        self.writer().emit_mov_imm(R8, taken as u64);
+        self.writer().emit_alu_reg(0x01, RCX, R8);
+        self.increment_counter_at_rcx();
+    }
+
+    fn enter_frame(&mut self) {
+        // Each call takes 16 bytes of stack, so trap unless there's room for another
+        self.writer().emit_mov_reg(R8, RBX);
+        self.writer().emit_alu_reg(0x29, R8, RSP);
+        self.writer()
+            .emit_cmp_imm32(R8, (MAX_CALL_DEPTH * 16) as u32);
+        self.trap_unless(COND_B, trap::CALL_STACK_OVERFLOW);
+
+        // Advance the locals base past the caller's window of locals
+        self.load_frame_size_in_bytes(R8);
+        self.writer().emit_alu_reg(0x01, RDX, R8);
+    }
+
+    fn leave_frame(&mut self) {
+        self.load_frame_size_in_bytes(R8);
+        self.writer().emit_alu_reg(0x29, RDX, R8);
+    }
+
+    fn call(&mut self, target: BlockTarget) -> usize {
+        // Pushing rbp alongside the return address keeps calls to 16 bytes of stack each, so
+        // the stack alignment is the same in every frame
+        self.writer().emit_push(RBP);
+        self.writer().emit_call_rel32(0);
+        self.record_jump(target);
+        let return_address = self.len();
+        self.writer().emit_pop(RBP);
+        return_address
+    }
+
+    fn push(&mut self, src: Reg) {
+        assert_ne!(src, Reg::GPR2);
+        self.writer().emit_cmp_imm32(R10, MAX_STACK_DEPTH as u32);
+        self.trap_unless(COND_B, trap::STACK_OVERFLOW);
+
+        self.stack_top_address(R8);
+        self.writer().emit_store(R8, 0, encode(src));
+        self.writer().emit_add_imm8(R10, 1);
+    }
+
+    fn pop(&mut self, dst: Reg) {
+        self.writer().emit_test(R10);
+        self.trap_unless(COND_NE, trap::STACK_UNDERFLOW);
+
+        self.writer().emit_add_imm8(R10, -1);
+        self.stack_top_address(R8);
+        self.writer().emit_load(encode(dst), R8, 0);
+    }
+
+    fn jump(&mut self, target: BlockTarget) {
+        // Branch to the target basic block (32-bit displacement)
+        self.writer().emit_jmp_rel32(0);
+        self.record_jump(target);
+    }
+
+    fn jump_conditional(&mut self, reg: Reg, true_target: BlockTarget, false_target: BlockTarget) {
+        // Compare reg with zero
+        self.writer().emit_test(encode(reg));
+
+        // Branch to false_target if reg is zero
+        self.writer().emit_jcc_rel32(COND_EQ, 0);
+        self.record_jump(false_target);
+
+        // Branch to true_target (unconditionally)
+        self.jump(true_target);
+    }
+
+    fn call_into_rust(&mut self, dst: Reg, func: Func) {
+        self.host_calls.push(func);
+
+        // The System V ABI lets the callee clobber every register holding VM state, and five
+        // pushes on top of the return address leave rsp 16-byte aligned for the call
+        for reg in [RDI, RSI, RDX, R9, R10] {
+            self.writer().emit_push(reg);
+        }
+
+        // Arguments come from r1 onwards into rdi, rsi and rdx, where rsi is loaded last as it
+        // holds the register array base
+        let args = [(RDI, 1), (RDX, 3), (RSI, 2)];
+        for (arg, reg) in args.into_iter().filter(|(_, reg)| *reg <= func.arity()) {
+            self.writer().emit_load(arg, RSI, reg);
+        }
+
+        self.writer().emit_mov_imm(R11, func.address());
+        self.writer().emit_call(R11);
+        if encode(dst) != RAX {
+            self.writer().emit_mov_reg(encode(dst), RAX);
+        }
+
+        for reg in [R10, R9, RDX, RSI, RDI] {
+            self.writer().emit_pop(reg);
+        }
+    }
+
+    fn brk(&mut self) {
+        self.writer().emit_int3();
+    }
+
+    fn ret(&mut self) {
+        // Return from the function
+        self.writer().emit_ret();
+    }
+
+    fn no_op(&mut self) {
+        self.writer().emit_nop();
+    }
+
+    fn host_calls(&self) -> &[Func] {
+        &self.host_calls
+    }
+
+    fn emit_veneers_if_needed(&mut self) {
+        // Every jump has a 32-bit displacement, so they all reach equally far
+    }
+
+    fn resolve_pending_branches(&mut self, _offsets: &[usize]) -> Result<(), LinkError> {
+        Ok(())
+    }
+
+    fn take_jumps(&mut self) -> Vec<(usize, BlockTarget)> {
+        std::mem::take(&mut self.jumps)
+    }
+
+    fn link_jump(&mut self, target_offset: usize, instr_offset: usize) -> Result<(), LinkError> {
+        // JMP rel32, Jcc rel32 and CALL rel32 all end with their displacement, which is relative
+        // to the address of the next instruction
+        let next_instr_offset = instr_offset as i64 + 4;
+        let displacement =
+            i32::try_from(target_offset as i64 - next_instr_offset).map_err(|_| {
+                LinkError::OutOfRange {
+                    instr_offset,
+                    target_offset,
+                }
+            })?;
+
+        self.output[instr_offset..instr_offset + 4].copy_from_slice(&displacement.to_le_bytes());
+        Ok(())
+    }
+}
+
+impl X64Assembler {
+    fn writer(&mut self) -> X64Writer<'_> {
+        X64Writer(&mut self.output)
+    }
+
+    /// Records the displacement of the jump just emitted, to be linked to `target`
+    fn record_jump(&mut self, target: BlockTarget) {
+        self.jumps.push((self.len() - 4, target));
+    }
+
+    fn load_frame_size_in_bytes(&mut self, dst: u8) {
+        assert_eq!(VM::FRAME_SIZE_OFFSET % 8, 0);
+        self.writer().emit_load(dst, RDI, VM::FRAME_SIZE_OFFSET / 8);
+        self.writer().emit_shl_imm8(dst, 3);
+    }
+
+    /// Sets `dst` to the address of the first free slot of the operand stack
+    fn stack_top_address(&mut self, dst: u8) {
+        self.writer().emit_mov_reg(dst, R10);
+        self.writer().emit_shl_imm8(dst, 3);
+        self.writer().emit_alu_reg(0x01, dst, R9);
+    }
+
+    /// Adds one to the counter rcx indexes, going through r8
+    fn increment_counter_at_rcx(&mut self) {
+        assert_eq!(VM::COUNTERS_BASE_OFFSET % 8, 0);
+        self.writer()
+            .emit_load(R8, RDI, VM::COUNTERS_BASE_OFFSET / 8);
+        self.writer().emit_shl_imm8(RCX, 3);
+        self.writer().emit_alu_reg(0x01, R8, RCX);
+        self.writer().emit_load(RCX, R8, 0);
+        self.writer().emit_add_imm8(RCX, 1);
+        self.writer().emit_store(R8, 0, RCX);
+    }
+
+    /// Jumps over a trap with `code` when `cond` holds
+    fn trap_unless(&mut self, cond: u8, code: u8) {
+        let skip = self.writer().emit_jcc_rel8(cond);
+        self.trap(code);
+        self.bind_rel8(skip);
+    }
+
+    /// Points the 8-bit displacement at `offset` to the current end of the output
+    fn bind_rel8(&mut self, offset: usize) {
+        let displacement = i8::try_from(self.output.len() - (offset + 1))
+            .expect("short jump displacement should fit in 8 bits");
+        self.output[offset] = displacement as u8;
+    }
+}
+
+impl std::ops::Deref for X64Assembler {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.output
+    }
+}
+
+struct X64Writer<'a>(&'a mut Vec<u8>);
+
+impl<'a> X64Writer<'a> {
+    pub fn emit_mov_reg(&mut self, dst: u8, src: u8) {
+        // MOV r/m64, r64
+        self.emit_rex(true, src, dst);
+        self.emit8(0x89);
+        self.emit_modrm_reg(src, dst);
+    }
+
+    pub fn emit_mov_imm(&mut self, dst: u8, imm: u64) {
+        match u32::try_from(imm) {
+            Ok(imm) => {
+                // MOV r32, imm32 (zero-extends into the upper 32 bits)
+                self.emit_rex(false, 0, dst);
+                self.emit8(0xb8 + (dst & 0b111));
+                self.emit32(imm);
+            }
+            Err(_) => {
+                // MOV r64, imm64
+                self.emit_rex(true, 0, dst);
+                self.emit8(0xb8 + (dst & 0b111));
+                self.emit64(imm);
+            }
+        }
+    }
+
+    pub fn emit_store(&mut self, base: u8, index: usize, src: u8) {
+        // MOV [base + index * 8], r64
+        self.emit_rex(true, src, base);
+        self.emit8(0x89);
+        self.emit_modrm_mem(src, base, index);
+    }
+
+    pub fn emit_load(&mut self, dst: u8, base: u8, index: usize) {
+        // MOV r64, [base + index * 8]
+        self.emit_rex(true, dst, base);
+        self.emit8(0x8b);
+        self.emit_modrm_mem(dst, base, index);
+    }
+
+    pub fn emit_add_imm8(&mut self, dst: u8, imm: i8) {
+        // ADD r/m64, imm8
+        self.emit_rex(true, 0, dst);
+        self.emit8(0x83);
+        self.emit_modrm_reg(0, dst);
+        self.emit8(imm as u8);
+    }
+
+    pub fn emit_shl_imm8(&mut self, dst: u8, imm: u8) {
+        // SHL r/m64, imm8
+        self.emit_rex(true, 0, dst);
+        self.emit8(0xc1);
+        self.emit_modrm_reg(4, dst);
+        self.emit8(imm);
+    }
+
+    pub fn emit_alu_reg(&mut self, op_code: u8, dst: u8, src: u8) {
+        // ADD/SUB/XOR r/m64, r64
+        self.emit_rex(true, src, dst);
+        self.emit8(op_code);
+        self.emit_modrm_reg(src, dst);
+    }
+
+    pub fn emit_alu_reg32(&mut self, op_code: u8, dst: u8, src: u8) {
+        // ADD/SUB/XOR r/m32, r32 (zero-extends into the upper 32 bits)
+        self.emit_rex(false, src, dst);
+        self.emit8(op_code);
+        self.emit_modrm_reg(src, dst);
+    }
+
+    pub fn emit_imul(&mut self, dst: u8, src: u8) {
+        // IMUL r64, r/m64 (the low 64 bits are the same for signed and unsigned operands)
+        self.emit_rex(true, dst, src);
+        self.emit8(0x0f);
+        self.emit8(0xaf);
+        self.emit_modrm_reg(dst, src);
+    }
+
+    pub fn emit_div(&mut self, src: u8) {
+        // DIV r/m64 (unsigned divide of rdx:rax)
+        self.emit_rex(true, 0, src);
+        self.emit8(0xf7);
+        self.emit_modrm_reg(6, src);
+    }
+
+    pub fn emit_cmp(&mut self, lhs: u8, rhs: u8) {
+        // CMP r/m64, r64 (sets flags from lhs - rhs)
+        self.emit_rex(true, rhs, lhs);
+        self.emit8(0x39);
+        self.emit_modrm_reg(rhs, lhs);
+    }
+
+    pub fn emit_cmp_imm32(&mut self, lhs: u8, imm: u32) {
+        // CMP r/m64, imm32 (sign-extended)
+        self.emit_rex(true, 0, lhs);
+        self.emit8(0x81);
+        self.emit_modrm_reg(7, lhs);
+        self.emit32(imm);
+    }
+
+    pub fn emit_test(&mut self, reg: u8) {
+        // TEST r/m64, r64
+        self.emit_rex(true, reg, reg);
+        self.emit8(0x85);
+        self.emit_modrm_reg(reg, reg);
+    }
+
+    pub fn emit_setcc(&mut self, cond: u8, dst: u8) {
+        // SETcc r/m8 (REX is always emitted so that dst selects sil/dil rather than dh/bh)
+        self.emit8(0x40 | ((dst >> 3) & 1));
+        self.emit8(0x0f);
+        self.emit8(0x90 + cond);
+        self.emit_modrm_reg(0, dst);
+    }
+
+    pub fn emit_movzx_byte(&mut self, dst: u8) {
+        // MOVZX r64, r/m8
+        self.emit_rex(true, dst, dst);
+        self.emit8(0x0f);
+        self.emit8(0xb6);
+        self.emit_modrm_reg(dst, dst);
+    }
+
+    pub fn emit_jmp_rel32(&mut self, rel32: i32) {
+        // JMP rel32
+        self.emit8(0xe9);
+        self.emit32(rel32 as u32);
+    }
+
+    pub fn emit_jcc_rel32(&mut self, cond: u8, rel32: i32) {
+        // Jcc rel32
+        self.emit8(0x0f);
+        self.emit8(0x80 + cond);
+        self.emit32(rel32 as u32);
+    }
+
+    /// Emits a Jcc rel8 with a zero displacement, returning the offset of the displacement
+    pub fn emit_jcc_rel8(&mut self, cond: u8) -> usize {
+        self.emit8(0x70 + cond);
+        self.emit8(0);
+        self.0.len() - 1
+    }
+
+    pub fn emit_push(&mut self, src: u8) {
+        // PUSH r64
+        self.emit_rex(false, 0, src);
+        self.emit8(0x50 + (src & 0b111));
+    }
+
+    pub fn emit_pop(&mut self, dst: u8) {
+        // POP r64
+        self.emit_rex(false, 0, dst);
+        self.emit8(0x58 + (dst & 0b111));
+    }
+
+    pub fn emit_call_rel32(&mut self, rel32: i32) {
+        // CALL rel32
+        self.emit8(0xe8);
+        self.emit32(rel32 as u32);
+    }
+
+    pub fn emit_call(&mut self, target: u8) {
+        // CALL r/m64
+        self.emit_rex(false, 0, target);
+        self.emit8(0xff);
+        self.emit_modrm_reg(2, target);
+    }
+
+    pub fn emit_ret(&mut self) {
+        // RET
+        self.emit8(0xc3);
+    }
+
+    pub fn emit_int3(&mut self) {
+        // INT3
+        self.emit8(0xcc);
+    }
+
+    pub fn emit_nop(&mut self) {
+        // NOP
+        self.emit8(0x90);
+    }
+
+    /// Emits a REX prefix when one is needed, `w` selects 64-bit operand size
+    fn emit_rex(&mut self, w: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | ((w as u8) << 3) | (((reg >> 3) & 1) << 2) | ((rm >> 3) & 1);
+        if rex != 0x40 {
+            self.emit8(rex);
+        }
+    }
+
+    fn emit_modrm_reg(&mut self, reg: u8, rm: u8) {
+        self.emit8(0b11_000_000 | ((reg & 0b111) << 3) | (rm & 0b111));
+    }
+
+    fn emit_modrm_mem(&mut self, reg: u8, base: u8, index: usize) {
+        let disp32 = u32::try_from(index * 8).expect("memory operand offset too large");
+        self.emit8(0b10_000_000 | ((reg & 0b111) << 3) | (base & 0b111));
+        if base & 0b111 == RSP {
+            // rsp/r12 as a base register requires a SIB byte
+            self.emit8(0x24);
+        }
+        self.emit32(disp32);
+    }
+
+    fn emit8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn emit32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn emit64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+}
@@ -0,0 +1,22 @@
+pub mod bytecode;
+pub mod cfg;
+pub mod differential;
+pub mod error;
+pub mod host;
+pub mod interpreter;
+pub mod jit;
+pub mod optimizer;
+pub mod parser;
+pub mod profile;
+#[cfg(test)]
+mod testing;
+pub mod tiered;
+pub mod verifier;
+pub mod vm;
+
+pub fn env_var_flag_is_set(key: &str) -> bool {
+    std::env::var(key)
+        .ok()
+        .filter(|x| matches!(x.trim().parse(), Ok(1_usize)))
+        .is_some()
+}
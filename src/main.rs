@@ -1,139 +1,286 @@
 use std::fmt::Display;
 
-use parser::Parser;
-use vm::BlockTarget;
+use cheekyjit::{
+    bytecode, differential,
+    error::Location,
+    host::{Func, HostRegistry},
+    interpreter::Status,
+    jit::{self, ExitStatus},
+    optimizer::{self, Level},
+    parser::Parser,
+    profile::Profile,
+    tiered::{self, Tiered},
+    verifier, vm,
+};
 
-mod jit;
-mod parser;
-mod vm;
+/// How a program is executed, chosen by an optional leading flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Jit,
+    Interpret,
+    Verify,
+    Tiered,
+}
 
 fn main() {
     let mut vm = vm::VM::new(8, 4);
+    let host = host_functions();
     let program_iters = 100_000_000;
 
-    match std::env::args().skip(1).next().as_deref() {
-        Some("--no-jit") => {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mode, args) = match args.split_first() {
+        Some((flag, rest)) if flag == "--no-jit" => (Mode::Interpret, rest),
+        Some((flag, rest)) if flag == "--verify" => (Mode::Verify, rest),
+        Some((flag, rest)) if flag == "--tiered" => (Mode::Tiered, rest),
+        _ => (Mode::Jit, &args[..]),
+    };
+    let (level, args) = match args.split_first() {
+        Some((flag, rest)) if flag.starts_with("-O") => match &flag[2..] {
+            "0" => (Level::None, rest),
+            "1" => (Level::Block, rest),
+            "2" => (Level::Program, rest),
+            _ => exit_with_usage_help(),
+        },
+        _ => (Level::None, args),
+    };
+    let (fuel, args) = match args.split_first() {
+        Some((flag, rest)) if flag == "--fuel" => match rest.split_first() {
+            Some((fuel, rest)) => (
+                Some(fuel.parse().unwrap_or_else(|_| exit_with_usage_help())),
+                rest,
+            ),
+            None => exit_with_usage_help(),
+        },
+        _ => (None, args),
+    };
+    vm.set_fuel(fuel);
+    let (profile_path, args) = match args.split_first() {
+        Some((flag, rest)) if flag == "--profile" => match rest.split_first() {
+            Some((path, rest)) => (Some(path.as_str()), rest),
+            None => exit_with_usage_help(),
+        },
+        _ => (None, args),
+    };
+
+    let configured = jit::Options::configured().unwrap_or_else(|err| exit_with_usage_error(err));
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {
             let program = sample_loop_program(program_iters);
             program.dump();
 
-            run_interpreted(&program, &mut vm)
-                .unwrap_or_else(|_| exit_with_error_msg("Failed to run program", ""));
+            run_program(&program, &mut vm, mode, level, configured, profile_path);
 
-            vm.dump();
             assert_eq!(
                 vm.locals[0].0, program_iters,
                 "program should set local[0] to 0"
             );
         }
-        Some("--nop") => {
-            let jit = jit::Jit::dummy();
+        ["--nop"] if mode == Mode::Jit => {
+            let jit = jit::Jit::dummy(configured.target);
             jit.dump();
 
             let executable = jit.into_exec();
-            executable.run(&mut vm);
+            match executable.run(&mut vm) {
+                Ok(ExitStatus::Exited(_)) => {}
+                Ok(ExitStatus::OutOfFuel) => unreachable!("the dummy program isn't metered"),
+                Ok(ExitStatus::Suspended) => unreachable!("only compiled regions suspend"),
+                Err(trap) => exit_with_error_msg("Program trapped", trap),
+            }
         }
-        Some("-i") => {
-            let path = std::env::args()
-                .skip(2)
-                .next()
-                .unwrap_or_else(|| exit_with_usage_help());
-
-            let code = std::fs::read_to_string(&path).unwrap_or_else(|err| {
-                exit_with_error_msg(&format!("Failed to read provided file: {path}"), err)
-            });
-            let program = Parser::new(&code).parse().unwrap_or_else(|err| {
-                exit_with_error_msg(&format!("Failed to compile program: {path}"), err)
-            });
-
+        ["-i", path] => {
+            let program = load_program(path, &host);
             program.dump();
 
-            let jit = jit::Jit::compile(&program);
-            jit.dump();
-
-            let executable = jit.into_exec();
-            executable.run(&mut vm);
-            vm.dump();
+            let result = run_program(&program, &mut vm, mode, level, configured, profile_path);
+            exit_with_result(result);
         }
-        None => {
-            let program = sample_loop_program(program_iters);
-            program.dump();
-
-            let jit = jit::Jit::compile(&program);
-            jit.dump();
-
-            let executable = jit.into_exec();
-            executable.run(&mut vm);
-            vm.dump();
-
-            assert_eq!(
-                vm.locals[0].0, program_iters,
-                "program should set local[0] to 0"
-            );
+        ["-c", path, output_path] if mode == Mode::Jit => {
+            let program = optimizer::optimize(&load_program(path, &host), level);
+            std::fs::write(output_path, bytecode::encode(&program)).unwrap_or_else(|err| {
+                exit_with_error_msg(&format!("Failed to write bytecode: {output_path}"), err)
+            });
+            eprintln!("compiled {path} to {output_path}");
         }
-        Some(_) => {
+        _ => {
             exit_with_usage_help();
         }
     }
 }
 
-fn run_interpreted(program: &vm::Program, vm: &mut vm::VM) -> Result<(), ()> {
-    let current_block = program.blocks.first().ok_or(())?.clone();
-    let mut current_block = BlockTarget::new(current_block);
-    let mut instruction_index = 0;
-
-    while instruction_index < current_block.len() {
-        let instruction = &current_block.instruction(instruction_index);
-        match &instruction {
-            vm::Instruction::LoadImmediate { value } => *vm.accum_reg_mut() = *value,
-            vm::Instruction::Load { reg } => *vm.accum_reg_mut() = get_reg(vm, reg)?,
-            vm::Instruction::Store { reg } => *get_reg_mut(vm, reg)? = *vm.accum_reg(),
-            vm::Instruction::SetLocal { local } => *get_local_mut(vm, local)? = *vm.accum_reg(),
-            vm::Instruction::GetLocal { local } => *vm.accum_reg_mut() = get_local(vm, local)?,
-            vm::Instruction::Increment => vm.accum_reg_mut().0 += 1,
-            vm::Instruction::LessThan { lhs } => vm.accum_reg_mut().0 = less_than(vm, lhs)?,
-            vm::Instruction::Breakpoint => unsafe { std::arch::asm!("brk 0") },
-            vm::Instruction::Exit => return Ok(()),
-            vm::Instruction::Jump { target } => {
-                jump(&mut current_block, &mut instruction_index, target)
+/// Verifies and optimizes `program` then runs it to completion, either with the interpreter or
+/// as JIT compiled code built from the `configured` options, returning the result it exited
+/// with. Compiled code is only metered when the VM has limited fuel. With a `profile_path`,
+/// the run is profiled and the profile is reported and written there once the program exits.
+fn run_program(
+    original: &vm::Program,
+    vm: &mut vm::VM,
+    mode: Mode,
+    level: Level,
+    configured: jit::Options,
+    profile_path: Option<&str>,
+) -> Option<vm::Value> {
+    verifier::verify(original, vm.shape())
+        .unwrap_or_else(|err| exit_with_error_msg("Failed to verify program", err));
+
+    let program = &optimizer::optimize(original, level);
+    if level != Level::None {
+        eprintln!("Optimized program:");
+        program.dump();
+    }
+
+    if profile_path.is_some() {
+        vm.start_profiling(program);
+    }
+    let options = jit::Options {
+        fuel: vm.fuel().is_some(),
+        profile: vm.is_profiling(),
+        ..configured
+    };
+    let result = match mode {
+        Mode::Jit => {
+            let jit = jit::Jit::compile_with(program, options)
+                .unwrap_or_else(|err| exit_with_error_msg("Failed to link program", err));
+            jit.dump();
+
+            let executable = jit.into_exec();
+            match executable.run(vm) {
+                Ok(ExitStatus::Exited(result)) => result,
+                Ok(ExitStatus::OutOfFuel) => exit_out_of_fuel(vm, program),
+                Ok(ExitStatus::Suspended) => unreachable!("only compiled regions suspend"),
+                Err(trap) => {
+                    vm.dump();
+                    exit_with_error_msg("Program trapped", trap);
+                }
             }
-            vm::Instruction::JumpConditional {
-                true_target: t,
-                false_target: f,
-            } => {
-                let target = if vm.accum_reg().0 != 0 { t } else { f };
-                jump(&mut current_block, &mut instruction_index, target)
+        }
+        Mode::Interpret => {
+            let status = vm.run(program);
+            exit_status(status, vm, program)
+        }
+        Mode::Tiered => {
+            let threshold =
+                tiered::configured_threshold().unwrap_or_else(|err| exit_with_usage_error(err));
+            let mut tiered = Tiered::new(program, options, threshold);
+            if let Ok(path) = std::env::var("JIT_PROFILE") {
+                tiered
+                    .seed(&load_profile(&path))
+                    .unwrap_or_else(|err| exit_with_error_msg("Failed to link program", err));
             }
+            let status = tiered
+                .run(vm)
+                .unwrap_or_else(|err| exit_with_error_msg("Failed to link program", err));
+            eprintln!("compiled {} hot block(s)", tiered.hot_blocks().len());
+            exit_status(status, vm, program)
         }
-        instruction_index += 1;
-    }
+        Mode::Verify => {
+            let report = differential::cross_check_optimized(original, program, vm, options)
+                .unwrap_or_else(|err| exit_with_error_msg("Failed to link program", err));
+            if report.status == Status::OutOfFuel {
+                exit_out_of_fuel(&report.interpreted, original);
+            }
+            if !report.is_match() {
+                report.interpreted.dump();
+                report.compiled.dump();
+                exit_with_error_msg("Interpreter and JIT diverged", report);
+            }
+            eprintln!("{report}");
+            *vm = report.compiled;
+            exit_status(report.status, vm, program)
+        }
+    };
+    vm.dump();
 
-    fn get_reg(vm: &vm::VM, reg: &vm::VMRegister) -> Result<vm::Value, ()> {
-        vm.registers.get(reg.0).ok_or(()).copied()
+    if let (Some(path), Some(profile)) = (profile_path, vm.profile(program)) {
+        eprintln!("Hot blocks:");
+        eprint!("{profile}");
+        std::fs::write(path, profile.encode()).unwrap_or_else(|err| {
+            exit_with_error_msg(&format!("Failed to write profile: {path}"), err)
+        });
+        eprintln!("wrote profile to {path}");
     }
+    result
+}
 
-    fn get_reg_mut<'a>(vm: &'a mut vm::VM, reg: &vm::VMRegister) -> Result<&'a mut vm::Value, ()> {
-        vm.registers.get_mut(reg.0).ok_or(())
+/// The result the program exited with, reporting a trap or running out of fuel instead
+fn exit_status(status: Status, vm: &vm::VM, program: &vm::Program) -> Option<vm::Value> {
+    match status {
+        Status::Exited(result) => result,
+        Status::Trapped(trap) => {
+            vm.dump();
+            exit_with_error_msg("Program trapped", trap);
+        }
+        Status::OutOfFuel => exit_out_of_fuel(vm, program),
+        Status::Running => unreachable!("the interpreter only returns once the program stops"),
     }
+}
 
-    fn get_local(vm: &vm::VM, local: &vm::VMLocal) -> Result<vm::Value, ()> {
-        vm.locals.get(local.0).ok_or(()).copied()
+/// Exits with the result the program handed back with `RET`, if it had one, so that shell
+/// scripts can read it from the exit status. Only its low 8 bits survive on most platforms.
+fn exit_with_result(result: Option<vm::Value>) {
+    if let Some(result) = result {
+        eprintln!("program exited with result {}", result.0);
+        std::process::exit(result.0 as i32)
     }
+}
 
-    fn get_local_mut<'a>(vm: &'a mut vm::VM, local: &vm::VMLocal) -> Result<&'a mut vm::Value, ()> {
-        vm.locals.get_mut(local.0).ok_or(())
-    }
+/// Reports the instruction the program would resume from once refuelled, and the VM it left
+fn exit_out_of_fuel(vm: &vm::VM, program: &vm::Program) -> ! {
+    vm.dump();
+    let location = match vm.location(program) {
+        Some(Location { block, instruction }) => format!("stopped before {block}[{instruction}]"),
+        None => "stopped before the entry block".to_string(),
+    };
+    exit_with_error_msg("Program ran out of fuel", location)
+}
 
-    fn less_than(vm: &vm::VM, lhs: &vm::VMRegister) -> Result<u64, ()> {
-        let is_lt = get_reg(vm, lhs)?.0 < vm.accum_reg().0;
-        Ok(if is_lt { 1 } else { 0 })
+/// Loads either `.cj` source or `.cjb` bytecode, telling them apart by the bytecode header
+fn load_program(path: &str, host: &HostRegistry) -> vm::Program {
+    let bytes = std::fs::read(path).unwrap_or_else(|err| {
+        exit_with_error_msg(&format!("Failed to read provided file: {path}"), err)
+    });
+
+    if bytecode::is_bytecode(&bytes) {
+        return bytecode::decode(&bytes, host).unwrap_or_else(|err| {
+            exit_with_error_msg(&format!("Failed to load bytecode: {path}"), err)
+        });
     }
 
-    fn jump(dst: &mut BlockTarget, instruction_index: &mut usize, target: &vm::BlockTarget) {
-        *dst = target.clone();
-        *instruction_index = 0;
+    let code = String::from_utf8(bytes).unwrap_or_else(|err| {
+        exit_with_error_msg(&format!("Failed to read provided file: {path}"), err)
+    });
+    let parser = Parser::new(&code).with_host_functions(host);
+    parser.parse().unwrap_or_else(|err| {
+        exit_with_error_msg(&format!("Failed to compile program: {path}"), err)
+    })
+}
+
+/// Loads a profile written by `--profile` in an earlier run
+fn load_profile(path: &str) -> Profile {
+    let text = std::fs::read_to_string(path)
+        .unwrap_or_else(|err| exit_with_error_msg(&format!("Failed to read profile: {path}"), err));
+    Profile::decode(&text)
+        .unwrap_or_else(|err| exit_with_error_msg(&format!("Failed to load profile: {path}"), err))
+}
+
+/// The host functions programs run from the command line can call with `CALL_NATIVE`
+fn host_functions() -> HostRegistry {
+    extern "C" fn print(x: u64) -> u64 {
+        println!("{x}");
+        x
+    }
+    extern "C" fn pow(base: u64, exp: u64) -> u64 {
+        base.wrapping_pow(exp.try_into().unwrap_or(u32::MAX))
+    }
+    extern "C" fn clamp(x: u64, min: u64, max: u64) -> u64 {
+        x.max(min).min(max)
     }
 
-    Ok(())
+    let mut host = HostRegistry::default();
+    host.register("print", Func::FnSingleInt64WithReturnInt64(print));
+    host.register("pow", Func::FnDoubleInt64WithReturnInt64(pow));
+    host.register("clamp", Func::FnTripleInt64WithReturnInt64(clamp));
+    host
 }
 
 fn sample_loop_program(iters: u64) -> vm::Program {
@@ -164,18 +311,19 @@ LOOP0_END:
         .expect("failed to parse sample program;")
 }
 
-pub fn env_var_flag_is_set(key: &str) -> bool {
-    std::env::var(key)
-        .ok()
-        .filter(|x| matches!(x.trim().parse(), Ok(1_usize)))
-        .is_some()
-}
-
 fn exit_with_usage_help() -> ! {
-    eprintln!("Usage: cheekyjit [--no-jit|--nop|-i <bytecode_fpath>]");
+    eprintln!(
+        "Usage: cheekyjit [--no-jit|--verify|--tiered] [-O<0|1|2>] [--fuel <n>] [--profile <profile_fpath>] [-i <bytecode_fpath>] | --nop | [-O<0|1|2>] -c <source_fpath> <output_fpath>"
+    );
     std::process::exit(1)
 }
 
+/// Reports `err` with whatever the user got wrong about running the tool, then the usage help
+fn exit_with_usage_error(err: impl Display) -> ! {
+    eprintln!("ERROR: {err}");
+    exit_with_usage_help()
+}
+
 fn exit_with_error_msg(msg: &str, err: impl Display) -> ! {
     eprintln!("ERROR: {msg}");
     eprintln!("    {err}");
@@ -0,0 +1,333 @@
+//! Rewrites programs into equivalent ones that do less work, before they are interpreted or
+//! JIT compiled.
+//!
+//! Programs are assumed to follow the interpreter's semantics, so running off the end of a
+//! block exits the program rather than falling through to whichever block comes next.
+
+use crate::{
+    cfg::{leaves_block, Cfg},
+    vm::{BlockTarget, Instruction, Program, VMRegister, Value},
+};
+
+/// How hard the optimizer works, as chosen by `-O0`, `-O1` and `-O2` on the command line
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Leaves the program as it is
+    #[default]
+    None,
+    /// Folds increments of constants and drops accumulator writes that are never read, one
+    /// block at a time
+    Block,
+    /// Also threads jumps through blocks that only jump elsewhere, and drops blocks that can't
+    /// be reached from the entry block
+    Program,
+}
+
+/// Returns an optimized copy of `program`, leaving `program` itself untouched. The entry block
+/// stays first, and every block that is kept keeps its label.
+pub fn optimize(program: &Program, level: Level) -> Program {
+    let mut program = program.clone();
+
+    if level >= Level::Block {
+        for block in &mut program.blocks {
+            fold_constants(&mut block.instructions);
+            remove_dead_accumulator_writes(&mut block.instructions);
+        }
+    }
+
+    if level >= Level::Program {
+        thread_jumps(&mut program);
+        remove_unreachable_blocks(&mut program);
+    }
+    program
+}
+
+/// Replaces increments of a known accumulator with loads of the incremented value, leaving the
+/// loads they make redundant to `remove_dead_accumulator_writes`
+fn fold_constants(instructions: &mut [Instruction]) {
+    let mut accumulator: Option<Value> = None;
+    for instruction in instructions {
+        match instruction {
+            Instruction::LoadImmediate { value } => accumulator = Some(*value),
+            Instruction::Increment => {
+                if let Some(value) = &mut accumulator {
+                    value.0 = value.0.wrapping_add(1);
+                    *instruction = Instruction::LoadImmediate { value: *value };
+                }
+            }
+            _ if writes_accumulator(instruction) => accumulator = None,
+            _ => {}
+        }
+    }
+}
+
+/// Drops instructions whose only effect is to write the accumulator, when a later instruction
+/// in the block overwrites it before anything reads it
+fn remove_dead_accumulator_writes(instructions: &mut Vec<Instruction>) {
+    // Whatever runs after the block can see the accumulator
+    let mut live = true;
+    let mut keep = vec![true; instructions.len()];
+    for (instruction, keep) in instructions.iter().zip(&mut keep).rev() {
+        if !live && only_writes_accumulator(instruction) {
+            *keep = false;
+        } else if reads_accumulator(instruction) {
+            live = true;
+        } else if writes_accumulator(instruction) {
+            live = false;
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    instructions.retain(|_| keep.next().unwrap());
+}
+
+/// Points jumps and calls to a block that does nothing but jump elsewhere at the block it
+/// jumps to instead
+fn thread_jumps(program: &mut Program) {
+    let threaded: Vec<Vec<Instruction>> = program
+        .blocks
+        .iter()
+        .map(|block| {
+            let instructions = block.instructions.iter().cloned();
+            instructions
+                .map(|x| retarget(x, |target| thread(program, target)))
+                .collect()
+        })
+        .collect();
+
+    for (block, instructions) in program.blocks.iter_mut().zip(threaded) {
+        block.instructions = instructions;
+    }
+}
+
+/// Follows `target` through blocks that only jump elsewhere, unless they jump in a cycle
+fn thread(program: &Program, target: BlockTarget) -> BlockTarget {
+    let mut threaded = target;
+    let mut seen = vec![];
+    while let Some(block) = program.blocks.get(threaded.0) {
+        if seen.contains(&threaded) {
+            return target;
+        }
+        seen.push(threaded);
+
+        match block.instructions.as_slice() {
+            [Instruction::Jump { target }] => threaded = *target,
+            _ => break,
+        }
+    }
+    threaded
+}
+
+/// Drops the blocks that can't be reached from the entry block, renumbering the targets of
+/// the ones that are left. Instructions after the first one that leaves a block are dropped
+/// too, as they can never run and may jump to blocks that are gone.
+fn remove_unreachable_blocks(program: &mut Program) {
+    let cfg = Cfg::new(program);
+    let mut kept = 0..;
+    let targets: Vec<Option<BlockTarget>> = (0..program.blocks.len())
+        .map(|x| {
+            cfg.is_reachable(x)
+                .then(|| BlockTarget(kept.next().unwrap()))
+        })
+        .collect();
+
+    let blocks = std::mem::take(&mut program.blocks)
+        .into_iter()
+        .zip(&targets);
+    program.blocks = blocks
+        .filter(|(_, target)| target.is_some())
+        .map(|(mut block, _)| {
+            if let Some(end) = block.instructions.iter().position(leaves_block) {
+                block.instructions.truncate(end + 1);
+            }
+            let instructions = std::mem::take(&mut block.instructions).into_iter();
+            block.instructions = instructions
+                .map(|x| {
+                    retarget(x, |target| match targets.get(target.0) {
+                        Some(kept) => kept.expect("jump targets are reachable"),
+                        None => target,
+                    })
+                })
+                .collect();
+            block
+        })
+        .collect();
+}
+
+fn retarget(instruction: Instruction, f: impl Fn(BlockTarget) -> BlockTarget) -> Instruction {
+    match instruction {
+        Instruction::Jump { target } => Instruction::Jump { target: f(target) },
+        Instruction::Call { target } => Instruction::Call { target: f(target) },
+        Instruction::JumpConditional {
+            true_target,
+            false_target,
+        } => Instruction::JumpConditional {
+            true_target: f(true_target),
+            false_target: f(false_target),
+        },
+        instruction => instruction,
+    }
+}
+
+/// Whether `instruction` depends on the accumulator, counting anything that hands control to
+/// other code as a read since that code can see it
+fn reads_accumulator(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Load { reg } => *reg == VMRegister(0),
+        Instruction::LoadImmediate { .. }
+        | Instruction::GetLocal { .. }
+        | Instruction::Pop
+        | Instruction::Duplicate
+        | Instruction::Swap
+        | Instruction::CallNative { .. } => false,
+        Instruction::Store { .. }
+        | Instruction::SetLocal { .. }
+        | Instruction::Increment
+        | Instruction::Add { .. }
+        | Instruction::Subtract { .. }
+        | Instruction::Multiply { .. }
+        | Instruction::Divide { .. }
+        | Instruction::Modulo { .. }
+        | Instruction::Compare { .. }
+        | Instruction::Push
+        | Instruction::Breakpoint
+        | Instruction::Exit { .. }
+        | Instruction::Abort
+        | Instruction::Jump { .. }
+        | Instruction::JumpConditional { .. }
+        | Instruction::Call { .. }
+        | Instruction::Return => true,
+    }
+}
+
+/// Whether the accumulator may hold a different value once `instruction` has run
+fn writes_accumulator(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Store { .. }
+        | Instruction::SetLocal { .. }
+        | Instruction::Push
+        | Instruction::Duplicate
+        | Instruction::Swap
+        | Instruction::Breakpoint
+        | Instruction::Exit { .. }
+        | Instruction::Abort
+        | Instruction::Jump { .. }
+        | Instruction::JumpConditional { .. }
+        | Instruction::Return => false,
+        Instruction::LoadImmediate { .. }
+        | Instruction::Load { .. }
+        | Instruction::GetLocal { .. }
+        | Instruction::Increment
+        | Instruction::Add { .. }
+        | Instruction::Subtract { .. }
+        | Instruction::Multiply { .. }
+        | Instruction::Divide { .. }
+        | Instruction::Modulo { .. }
+        | Instruction::Compare { .. }
+        | Instruction::Pop
+        | Instruction::Call { .. }
+        | Instruction::CallNative { .. } => true,
+    }
+}
+
+/// Whether writing the accumulator is all `instruction` does, so that it can be dropped when
+/// nothing reads what it writes. Division and remainder can also trap, so they're kept.
+fn only_writes_accumulator(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::LoadImmediate { .. }
+            | Instruction::Load { .. }
+            | Instruction::GetLocal { .. }
+            | Instruction::Increment
+            | Instruction::Add { .. }
+            | Instruction::Subtract { .. }
+            | Instruction::Multiply { .. }
+            | Instruction::Compare { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, SAMPLES},
+        vm::BasicBlock,
+    };
+
+    /// Leaves a jump to an otherwise unreachable block after the block has returned
+    const DEAD_JUMP: &str = r#"
+ENTRY:
+  LOAD_INT32 3
+  RET
+  JUMP #DEAD
+DEAD:
+  RET
+"#;
+
+    /// Divides by zero, then overwrites the quotient that was never computed
+    const DEAD_DIVISION: &str = r#"
+ENTRY:
+  LOAD_INT32 7
+  DIV r2
+  LOAD_INT32 5
+  RET
+"#;
+
+    #[test]
+    fn optimized_programs_run_like_the_originals() {
+        let programs = SAMPLES
+            .into_iter()
+            .chain([("DEAD_JUMP", DEAD_JUMP), ("DEAD_DIVISION", DEAD_DIVISION)]);
+        for (name, code) in programs {
+            let program = testing::parse(code);
+            let mut expected = testing::vm();
+            let expected_status = expected.run(&program);
+
+            for level in [Level::None, Level::Block, Level::Program] {
+                let mut vm = testing::vm();
+                let status = vm.run(&optimize(&program, level));
+                let context = format!("{name} at {level:?}");
+                assert_eq!(status, expected_status, "status differs for {context}");
+                testing::assert_same_state(&vm, &expected, &context);
+            }
+        }
+    }
+
+    #[test]
+    fn drops_dead_jumps_to_unreachable_blocks() {
+        let optimized = optimize(&testing::parse(DEAD_JUMP), Level::Program);
+        assert_eq!(optimized.blocks.len(), 1);
+        assert_eq!(optimized.blocks[0].instructions.len(), 2);
+    }
+
+    #[test]
+    fn keeps_divisions_that_can_trap() {
+        let optimized = optimize(&testing::parse(DEAD_DIVISION), Level::Block);
+        let divides = |x: &Instruction| matches!(x, Instruction::Divide { .. });
+        assert!(optimized.blocks[0].instructions.iter().any(divides));
+    }
+
+    #[test]
+    fn folds_increments_that_wrap() {
+        let mut instructions = vec![
+            Instruction::LoadImmediate {
+                value: Value(u64::MAX),
+            },
+            Instruction::Increment,
+        ];
+        fold_constants(&mut instructions);
+        let loads_zero = Instruction::LoadImmediate { value: Value(0) };
+        assert_eq!(instructions[1], loads_zero);
+
+        let mut vm = testing::vm();
+        *vm.accum_reg_mut() = Value(u64::MAX);
+        let program = Program {
+            blocks: vec![BasicBlock {
+                label: "ENTRY".to_string(),
+                instructions: vec![Instruction::Increment],
+            }],
+        };
+        vm.run(&program);
+        assert_eq!(*vm.accum_reg(), Value(0));
+    }
+}
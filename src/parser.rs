@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 
 use crate::{
-    parser::from_str::{VMLocalTarget, VMRegisterTarget},
+    error::{ParseError, ParseErrorKind, Span},
+    host::HostRegistry,
+    parser::from_str::{BlockReference, HostFunctionName, VMLocalTarget, VMRegisterTarget},
     vm,
 };
 
@@ -17,6 +19,8 @@ pub struct Parser<'a> {
     program: vm::Program,
     blocks_with_declarations: Vec<String>,
     block_targets: HashMap<String, vm::BlockTarget>,
+    block_references: HashMap<String, Span>,
+    host: HostRegistry,
 }
 
 impl<'a> Parser<'a> {
@@ -27,30 +31,38 @@ impl<'a> Parser<'a> {
             program: Default::default(),
             blocks_with_declarations: Default::default(),
             block_targets: Default::default(),
+            block_references: Default::default(),
+            host: Default::default(),
         }
     }
 
-    pub fn parse(mut self) -> Result<vm::Program, String> {
+    /// Resolves `CALL_NATIVE` instructions against the functions registered in `host`
+    pub fn with_host_functions(mut self, host: &HostRegistry) -> Self {
+        self.host = host.clone();
+        self
+    }
+
+    pub fn parse(mut self) -> Result<vm::Program, ParseError> {
         for (line_idx, line) in self.code.lines().enumerate() {
             let i = line_idx + 1; // line_num
             if !line.is_empty() && line.chars().next().unwrap().is_alphanumeric() {
                 self.state = ParserState::BlockStart;
             }
 
-            let line = line.trim();
-            let line = line.split_once("//").map_or(line, |(line, _)| line.trim());
-            if line.is_empty() {
+            let line = line.split_once("//").map_or(line, |(line, _)| line);
+            let tokens = Token::split(line, i);
+            let Some(first) = tokens.first() else {
                 continue;
-            }
+            };
 
             self.state = match self.state.clone() {
-                ParserState::BlockStart if line.ends_with(':') => {
-                    let block = self.parse_block_start(line);
+                ParserState::BlockStart if line.trim_end().ends_with(':') => {
+                    let block = self.parse_block_start(first)?;
                     ParserState::BlockInstructions(block)
                 }
-                ParserState::BlockStart => Err(format!("expected block label on line {i}"))?,
+                ParserState::BlockStart => Err(first.error(ParseErrorKind::ExpectedBlockLabel))?,
                 ParserState::BlockInstructions(block) => {
-                    self.parse_block_instructions(line, &block, i)?;
+                    self.parse_block_instructions(&tokens, block)?;
                     ParserState::BlockInstructions(block)
                 }
             }
@@ -60,166 +72,286 @@ impl<'a> Parser<'a> {
         Ok(self.program)
     }
 
-    fn parse_block_start(&mut self, line: &str) -> vm::BlockTarget {
-        let label = line
+    fn parse_block_start(&mut self, token: &Token) -> Result<vm::BlockTarget, ParseError> {
+        let label: String = token
+            .text
             .chars()
             .take_while(|x| x.is_alphanumeric() || *x == '_')
             .collect();
 
-        assert!(!self.blocks_with_declarations.contains(&label));
+        if self.blocks_with_declarations.contains(&label) {
+            return Err(token.error(ParseErrorKind::DuplicateLabel));
+        }
         self.blocks_with_declarations.push(label.clone());
-        self.get_or_create_block(label)
+        Ok(self.get_or_create_block(label))
     }
 
     fn parse_block_instructions(
         &mut self,
-        line: &str,
-        b: &vm::BlockTarget,
-        i: usize,
-    ) -> Result<(), String> {
-        Ok(match line.split_once(" ") {
-            Some(("LOAD_INT32", x)) => instruction::add_single_operand(b, x, i, |x: u64| {
-                Ok(vm::Instruction::LoadImmediate {
+        tokens: &[Token],
+        block: vm::BlockTarget,
+    ) -> Result<(), ParseError> {
+        let (m, ops) = tokens.split_first().unwrap();
+        let instr = match m.text {
+            "LOAD_INT32" => {
+                instruction::single_operand(m, ops, |x: u64| vm::Instruction::LoadImmediate {
                     value: vm::Value(x),
-                })
-            })?,
-            Some(("LOAD_REG", x)) => {
-                instruction::add_single_operand(b, x, i, |x: VMRegisterTarget| {
-                    Ok(vm::Instruction::Load { reg: x.0 })
-                })?
-            }
-            Some(("STORE_REG", x)) => {
-                instruction::add_single_operand(b, x, i, |x: VMRegisterTarget| {
-                    Ok(vm::Instruction::Store { reg: x.0 })
                 })?
             }
-            Some(("SET_LOCAL", x)) => {
-                instruction::add_single_operand(b, x, i, |x: VMLocalTarget| {
-                    Ok(vm::Instruction::SetLocal { local: x.0 })
+            "LOAD_REG" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Load { reg: x.0 }
+            })?,
+            "STORE_REG" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Store { reg: x.0 }
+            })?,
+            "SET_LOCAL" => instruction::single_operand(m, ops, |x: VMLocalTarget| {
+                vm::Instruction::SetLocal { local: x.0 }
+            })?,
+            "GET_LOCAL" => instruction::single_operand(m, ops, |x: VMLocalTarget| {
+                vm::Instruction::GetLocal { local: x.0 }
+            })?,
+            "ADD" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Add { rhs: x.0 }
+            })?,
+            "SUB" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Subtract { rhs: x.0 }
+            })?,
+            "MUL" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Multiply { rhs: x.0 }
+            })?,
+            "DIV" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Divide { rhs: x.0 }
+            })?,
+            "MOD" => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                vm::Instruction::Modulo { rhs: x.0 }
+            })?,
+            "JUMP" => {
+                instruction::single_operand(m, ops, |x: BlockReference| vm::Instruction::Jump {
+                    target: self.block_target_literal(x, &ops[0]),
                 })?
             }
-            Some(("GET_LOCAL", x)) => {
-                instruction::add_single_operand(b, x, i, |x: VMLocalTarget| {
-                    Ok(vm::Instruction::GetLocal { local: x.0 })
+            "JUMP_EITHER" => {
+                instruction::double_operand(m, ops, |t: BlockReference, f: BlockReference| {
+                    vm::Instruction::JumpConditional {
+                        true_target: self.block_target_literal(t, &ops[0]),
+                        false_target: self.block_target_literal(f, &ops[1]),
+                    }
                 })?
             }
-            Some(("LESS_THAN", x)) => {
-                instruction::add_single_operand(b, x, i, |x: VMRegisterTarget| {
-                    Ok(vm::Instruction::LessThan { lhs: x.0 })
+            "CALL" => {
+                instruction::single_operand(m, ops, |x: BlockReference| vm::Instruction::Call {
+                    target: self.block_target_literal(x, &ops[0]),
                 })?
             }
-            Some(("JUMP", x)) => instruction::add_single_operand(b, x, i, |x: String| {
-                Ok(vm::Instruction::Jump {
-                    target: self.block_target_literal(&x)?,
-                })
+            "CALL_NATIVE" => instruction::try_single_operand(m, ops, |x: HostFunctionName| {
+                let function = self.host.get(&x.0);
+                let function = function.ok_or(ParseErrorKind::UnknownHostFunction)?;
+                Ok(vm::Instruction::CallNative { function })
             })?,
-            Some(("JUMP_EITHER", x)) => {
-                instruction::add_double_operand(b, x, i, |t: String, f: String| {
-                    Ok(vm::Instruction::JumpConditional {
-                        true_target: self.block_target_literal(&t)?,
-                        false_target: self.block_target_literal(&f)?,
-                    })
-                })?
-            }
-            None if line == "INCR" => instruction::add_unary(b, vm::Instruction::Increment),
-            None if line == "BREAK" => instruction::add_unary(b, vm::Instruction::Breakpoint),
-            None if line == "RET" => instruction::add_unary(b, vm::Instruction::Exit),
-
-            Some((instr, _)) => Err(format!("unexpected instruction `{instr}` on line {i}"))?,
-            None => Err(format!("unexpected unary instruction `{line}` on line {i}"))?,
-        })
+            "RETURN" => instruction::unary(m, ops, vm::Instruction::Return)?,
+            "PUSH" => instruction::unary(m, ops, vm::Instruction::Push)?,
+            "POP" => instruction::unary(m, ops, vm::Instruction::Pop)?,
+            "DUP" => instruction::unary(m, ops, vm::Instruction::Duplicate)?,
+            "SWAP" => instruction::unary(m, ops, vm::Instruction::Swap)?,
+            "INCR" => instruction::unary(m, ops, vm::Instruction::Increment)?,
+            "BREAK" => instruction::unary(m, ops, vm::Instruction::Breakpoint)?,
+            "RET" => instruction::optional_operand(m, ops, |x: Option<VMRegisterTarget>| {
+                vm::Instruction::Exit {
+                    result: x.map(|x| x.0),
+                }
+            })?,
+            "ABORT" => instruction::unary(m, ops, vm::Instruction::Abort)?,
+
+            mnemonic => match instruction::comparison(mnemonic) {
+                Some(cond) => instruction::single_operand(m, ops, |x: VMRegisterTarget| {
+                    vm::Instruction::Compare { cond, lhs: x.0 }
+                })?,
+                None => Err(m.error(ParseErrorKind::UnknownMnemonic))?,
+            },
+        };
+        self.program.block_mut(block).instructions.push(instr);
+        Ok(())
     }
 
-    fn block_target_literal(&mut self, x: &str) -> Result<vm::BlockTarget, String> {
-        let block_label = from_str::extract_prefix(x.trim(), '#');
-        let block_label = block_label.map_err(|_| format!("unexpected block reference `{x}`"))?;
-        Ok(self.get_or_create_block(block_label))
+    fn block_target_literal(&mut self, x: BlockReference, token: &Token) -> vm::BlockTarget {
+        self.block_references
+            .entry(x.0.clone())
+            .or_insert(token.span);
+        self.get_or_create_block(x.0)
     }
 
     fn get_or_create_block(&mut self, block_label: String) -> vm::BlockTarget {
-        let block = self
+        let program = &mut self.program;
+        *self
             .block_targets
             .entry(block_label)
-            .or_insert_with(|| self.program.make_block())
-            .clone();
-        block
+            .or_insert_with_key(|label| program.make_block(label))
     }
 
-    fn validate_all_blocks_are_declared(&self) -> Result<(), String> {
-        let referenced_block_labels = self.block_targets.keys();
+    fn validate_all_blocks_are_declared(&self) -> Result<(), ParseError> {
+        let referenced_block_labels = self.block_references.iter();
 
-        let undeclared_blocks: Vec<_> = referenced_block_labels
-            .filter(|referenced_label| !self.blocks_with_declarations.contains(referenced_label))
-            .map(|x| x.as_str())
-            .collect();
+        let first_undeclared_block = referenced_block_labels
+            .filter(|(label, _)| !self.blocks_with_declarations.contains(label))
+            .min_by_key(|(_, span)| **span);
 
-        if undeclared_blocks.is_empty() {
-            Ok(())
-        } else {
-            let list = undeclared_blocks.join(", ");
-            Err(format!(
-                "missing declaration for the following block reference literal(s): {}",
-                list
-            ))
+        match first_undeclared_block {
+            None => Ok(()),
+            Some((label, span)) => Err(ParseError {
+                kind: ParseErrorKind::UndeclaredBlock,
+                span: *span,
+                token: format!("#{label}"),
+            }),
+        }
+    }
+}
+
+/// A whitespace separated word of source code, along with where it was found
+struct Token<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+impl<'a> Token<'a> {
+    fn split(line: &'a str, line_num: usize) -> Vec<Self> {
+        let mut tokens = vec![];
+        let mut start = None;
+        let chars = line.char_indices().chain([(line.len(), ' ')]);
+
+        for (column, (idx, c)) in chars.enumerate() {
+            match (start, c.is_whitespace()) {
+                (None, false) => start = Some((column, idx)),
+                (Some((start_column, start_idx)), true) => {
+                    tokens.push(Token {
+                        text: &line[start_idx..idx],
+                        span: Span {
+                            line: line_num,
+                            column: start_column + 1,
+                            len: column - start_column,
+                        },
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        tokens
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            kind,
+            span: self.span,
+            token: self.text.to_string(),
         }
     }
 }
 
 mod instruction {
-    use std::{fmt::Display, str::FromStr};
+    use crate::{
+        error::{ParseError, ParseErrorKind},
+        vm,
+    };
+
+    use super::{from_str::Operand, Token};
+
+    pub fn unary(
+        mnemonic: &Token,
+        operands: &[Token],
+        instr: vm::Instruction,
+    ) -> Result<vm::Instruction, ParseError> {
+        expect_operand_count(mnemonic, operands, 0)?;
+        Ok(instr)
+    }
 
-    use crate::vm;
+    pub fn single_operand<T: Operand>(
+        mnemonic: &Token,
+        operands: &[Token],
+        f: impl FnOnce(T) -> vm::Instruction,
+    ) -> Result<vm::Instruction, ParseError> {
+        expect_operand_count(mnemonic, operands, 1)?;
+        let x: T = parse_operand(&operands[0])?;
+        Ok(f(x))
+    }
+
+    /// Like `single_operand`, except the operand can be left out
+    pub fn optional_operand<T: Operand>(
+        mnemonic: &Token,
+        operands: &[Token],
+        f: impl FnOnce(Option<T>) -> vm::Instruction,
+    ) -> Result<vm::Instruction, ParseError> {
+        let x: Option<T> = match operands {
+            [] => None,
+            _ => {
+                expect_operand_count(mnemonic, operands, 1)?;
+                Some(parse_operand(&operands[0])?)
+            }
+        };
+        Ok(f(x))
+    }
+
+    /// Like `single_operand`, except `f` can reject the operand with an error at its token
+    pub fn try_single_operand<T: Operand>(
+        mnemonic: &Token,
+        operands: &[Token],
+        f: impl FnOnce(T) -> Result<vm::Instruction, ParseErrorKind>,
+    ) -> Result<vm::Instruction, ParseError> {
+        expect_operand_count(mnemonic, operands, 1)?;
+        let x: T = parse_operand(&operands[0])?;
+        f(x).map_err(|kind| operands[0].error(kind))
+    }
+
+    pub fn double_operand<T1: Operand, T2: Operand>(
+        mnemonic: &Token,
+        operands: &[Token],
+        f: impl FnOnce(T1, T2) -> vm::Instruction,
+    ) -> Result<vm::Instruction, ParseError> {
+        expect_operand_count(mnemonic, operands, 2)?;
+        let x1: T1 = parse_operand(&operands[0])?;
+        let x2: T2 = parse_operand(&operands[1])?;
+        Ok(f(x1, x2))
+    }
+
+    /// Maps a comparison mnemonic onto the relation it tests, where unsuffixed mnemonics
+    /// compare unsigned values and `_SIGNED` ones compare two's complement values
+    pub fn comparison(mnemonic: &str) -> Option<vm::Comparison> {
+        let cond = match mnemonic {
+            "EQUAL" => vm::Comparison::Equal,
+            "NOT_EQUAL" => vm::Comparison::NotEqual,
+            "LESS_THAN" => vm::Comparison::LessThan,
+            "LESS_EQUAL" => vm::Comparison::LessOrEqual,
+            "GREATER_THAN" => vm::Comparison::GreaterThan,
+            "GREATER_EQUAL" => vm::Comparison::GreaterOrEqual,
+            "LESS_THAN_SIGNED" => vm::Comparison::SignedLessThan,
+            "LESS_EQUAL_SIGNED" => vm::Comparison::SignedLessOrEqual,
+            "GREATER_THAN_SIGNED" => vm::Comparison::SignedGreaterThan,
+            "GREATER_EQUAL_SIGNED" => vm::Comparison::SignedGreaterOrEqual,
+            _ => return None,
+        };
+        Some(cond)
+    }
+
+    fn parse_operand<T: Operand>(token: &Token) -> Result<T, ParseError> {
+        token.text.parse().map_err(|_| {
+            token.error(ParseErrorKind::BadOperand {
+                expected: T::EXPECTED,
+            })
+        })
+    }
 
-    pub fn add_unary(block: &vm::BlockTarget, instr: vm::Instruction) {
-        block.append(instr);
-    }
-
-    pub fn add_single_operand<T>(
-        block: &vm::BlockTarget,
-        x: &str,
-        line_num: usize,
-        f: impl FnOnce(T) -> Result<vm::Instruction, String>,
-    ) -> Result<(), String>
-    where
-        T: FromStr,
-        T::Err: Display,
-    {
-        let x: T = x
-            .parse()
-            .map_err(|err| format!("failed to parse on line {}: {err}", line_num))?;
-
-        let instruction =
-            f(x).map_err(|err| format!("failed to parse on line {}: {err}", line_num))?;
-
-        Ok(add_unary(block, instruction))
-    }
-
-    pub fn add_double_operand<T1, T2>(
-        block: &vm::BlockTarget,
-        x: &str,
-        line_num: usize,
-        f: impl FnOnce(T1, T2) -> Result<vm::Instruction, String>,
-    ) -> Result<(), String>
-    where
-        T1: FromStr,
-        T1::Err: Display,
-        T2: FromStr,
-        T2::Err: Display,
-    {
-        let (x1, x2) = x
-            .split_once(" ")
-            .ok_or_else(|| format!("failed to parse instruction operands on line {}", line_num))?;
-        let x1: T1 = x1
-            .parse()
-            .map_err(|err| format!("failed to parse on line {}: {err}", line_num))?;
-        let x2: T2 = x2
-            .parse()
-            .map_err(|err| format!("failed to parse on line {}: {err}", line_num))?;
-
-        let instruction =
-            f(x1, x2).map_err(|err| format!("failed to parse on line {}: {err}", line_num))?;
-
-        Ok(add_unary(block, instruction))
+    fn expect_operand_count(
+        mnemonic: &Token,
+        operands: &[Token],
+        expected: usize,
+    ) -> Result<(), ParseError> {
+        if operands.len() == expected {
+            Ok(())
+        } else {
+            Err(mnemonic.error(ParseErrorKind::OperandCount {
+                expected,
+                found: operands.len(),
+            }))
+        }
     }
 }
 
@@ -228,32 +360,74 @@ mod from_str {
 
     use crate::vm;
 
+    /// An instruction operand, described by `EXPECTED` in errors when it fails to parse
+    pub trait Operand: FromStr {
+        const EXPECTED: &'static str;
+    }
+
+    impl Operand for u64 {
+        const EXPECTED: &'static str = "an unsigned integer literal";
+    }
+
     pub struct VMRegisterTarget(pub vm::VMRegister);
 
     impl FromStr for VMRegisterTarget {
-        type Err = String;
+        type Err = ();
 
         fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let x = extract_prefix(s, 'r');
-            Ok(Self(vm::VMRegister(x.map_err(|_| {
-                format!("unexpected register literal `{s}`")
-            })?)))
+            Ok(Self(vm::VMRegister(extract_prefix(s, 'r')?)))
         }
     }
 
+    impl Operand for VMRegisterTarget {
+        const EXPECTED: &'static str = "a register literal like `r0`";
+    }
+
     pub struct VMLocalTarget(pub vm::VMLocal);
 
     impl FromStr for VMLocalTarget {
-        type Err = String;
+        type Err = ();
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Ok(Self(vm::VMLocal(extract_prefix(s, '.')?)))
+        }
+    }
+
+    impl Operand for VMLocalTarget {
+        const EXPECTED: &'static str = "a local literal like `.0`";
+    }
+
+    pub struct BlockReference(pub String);
+
+    impl FromStr for BlockReference {
+        type Err = ();
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Ok(Self(extract_prefix(s, '#')?))
+        }
+    }
+
+    impl Operand for BlockReference {
+        const EXPECTED: &'static str = "a block reference literal like `#ENTRY`";
+    }
+
+    pub struct HostFunctionName(pub String);
+
+    impl FromStr for HostFunctionName {
+        type Err = ();
 
         fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let x = extract_prefix(s, '.');
-            Ok(Self(vm::VMLocal(
-                x.map_err(|_| format!("unexpected local literal `{s}`"))?,
-            )))
+            match s.chars().all(|x| x.is_alphanumeric() || x == '_') {
+                true => Ok(Self(s.to_string())),
+                false => Err(()),
+            }
         }
     }
 
+    impl Operand for HostFunctionName {
+        const EXPECTED: &'static str = "a host function name like `print`";
+    }
+
     pub fn extract_prefix<T: FromStr>(s: &str, pattern: char) -> Result<T, ()> {
         let split = s.trim().split_once(pattern).ok_or(());
         let parsed = split.and_then(|(x, y)| y.trim().parse::<T>().map(|y| (x, y)).map_err(|_| ()));
@@ -263,3 +437,119 @@ mod from_str {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(code: &str) -> ParseError {
+        Parser::new(code).parse().unwrap_err()
+    }
+
+    fn error(kind: ParseErrorKind, line: usize, column: usize, token: &str) -> ParseError {
+        ParseError {
+            kind,
+            span: Span {
+                line,
+                column,
+                len: token.chars().count(),
+            },
+            token: token.to_string(),
+        }
+    }
+
+    #[test]
+    fn reports_unknown_mnemonics() {
+        assert_eq!(
+            parse_error("ENTRY:\n  LOAD_INT32 1\n  JUMPP #ENTRY\n"),
+            error(ParseErrorKind::UnknownMnemonic, 3, 3, "JUMPP")
+        );
+    }
+
+    #[test]
+    fn reports_missing_operands_at_the_mnemonic() {
+        assert_eq!(
+            parse_error("ENTRY:\n  STORE_REG\n  RET\n"),
+            error(
+                ParseErrorKind::OperandCount {
+                    expected: 1,
+                    found: 0
+                },
+                2,
+                3,
+                "STORE_REG"
+            )
+        );
+        assert_eq!(
+            parse_error("ENTRY:\n  JUMP_EITHER #ENTRY\n"),
+            error(
+                ParseErrorKind::OperandCount {
+                    expected: 2,
+                    found: 1
+                },
+                2,
+                3,
+                "JUMP_EITHER"
+            )
+        );
+    }
+
+    #[test]
+    fn reports_bad_operands_at_the_operand() {
+        assert_eq!(
+            parse_error("ENTRY:\n  STORE_REG  x1\n  RET\n"),
+            error(
+                ParseErrorKind::BadOperand {
+                    expected: "a register literal like `r0`"
+                },
+                2,
+                14,
+                "x1"
+            )
+        );
+        assert_eq!(
+            parse_error("ENTRY:\n  LOAD_INT32 -1\n  RET\n"),
+            error(
+                ParseErrorKind::BadOperand {
+                    expected: "an unsigned integer literal"
+                },
+                2,
+                14,
+                "-1"
+            )
+        );
+    }
+
+    #[test]
+    fn reports_the_first_reference_to_an_undeclared_block() {
+        let code = "ENTRY:\n  CALL #MISSING\n  JUMP #ALSO_MISSING\nOTHER:\n  JUMP #MISSING\n";
+        assert_eq!(
+            parse_error(code),
+            error(ParseErrorKind::UndeclaredBlock, 2, 8, "#MISSING")
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_labels_at_the_second_declaration() {
+        assert_eq!(
+            parse_error("ENTRY:\n  RET\nLOOP:\n  RET\nLOOP:\n  RET\n"),
+            error(ParseErrorKind::DuplicateLabel, 5, 1, "LOOP:")
+        );
+    }
+
+    #[test]
+    fn reports_instructions_outside_a_block() {
+        assert_eq!(
+            parse_error("LOAD_INT32 1\n"),
+            error(ParseErrorKind::ExpectedBlockLabel, 1, 1, "LOAD_INT32")
+        );
+    }
+
+    #[test]
+    fn reports_unknown_host_functions_at_their_name() {
+        assert_eq!(
+            parse_error("ENTRY:\n  CALL_NATIVE print\n  RET\n"),
+            error(ParseErrorKind::UnknownHostFunction, 2, 15, "print")
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Counts how often each block of a program is entered, and which way its conditional jumps go
+
+use std::fmt::Display;
+
+use crate::{
+    error::{ProfileError, ProfileErrorKind},
+    vm::{Program, VM},
+};
+
+/// How many of `VM::counters` belong to each block, which are laid out in program order
+pub(crate) const COUNTERS_PER_BLOCK: usize = 3;
+/// Where the number of times a block was entered sits among its counters
+pub(crate) const ENTRIES: usize = 0;
+/// Where the number of times a block's conditional jump was taken sits among its counters, with
+/// the number of times it wasn't taken straight after
+pub(crate) const TAKEN: usize = 1;
+pub(crate) const NOT_TAKEN: usize = TAKEN + 1;
+
+/// The first line of every profile file
+const HEADER: &str = "# cheekyjit profile";
+
+/// What a profiling VM counted for one block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockProfile {
+    pub label: String,
+    /// How many times the block was started from its first instruction
+    pub entries: u64,
+    /// How many times a conditional jump in the block went to its true target
+    pub taken: u64,
+    /// How many times a conditional jump in the block went to its false target
+    pub not_taken: u64,
+}
+
+/// What a profiling VM counted for each block of a program, in program order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub blocks: Vec<BlockProfile>,
+}
+
+impl Profile {
+    /// The counts for the block labelled `label`, if the profile has any
+    pub fn block(&self, label: &str) -> Option<&BlockProfile> {
+        self.blocks.iter().find(|x| x.label == label)
+    }
+
+    /// The blocks that were entered at least once, most entered first
+    pub fn hot_blocks(&self) -> Vec<&BlockProfile> {
+        let mut hot: Vec<_> = self.blocks.iter().filter(|x| x.entries > 0).collect();
+        hot.sort_by_key(|x| std::cmp::Reverse(x.entries));
+        hot
+    }
+
+    /// The profile as a header line followed by a line for each block, holding its label, how
+    /// many times it was entered, and how many times its conditional jump was taken and not taken
+    pub fn encode(&self) -> String {
+        let mut text = format!("{HEADER}\n");
+        for block in &self.blocks {
+            let BlockProfile {
+                label,
+                entries,
+                taken,
+                not_taken,
+            } = block;
+            text.push_str(&format!("{label} {entries} {taken} {not_taken}\n"));
+        }
+        text
+    }
+
+    /// Reads a profile written by `encode`, ignoring blank lines
+    pub fn decode(text: &str) -> Result<Self, ProfileError> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+        let error = |kind, line| ProfileError { kind, line };
+        if lines.next().map(|(_, line)| line.trim_end()) != Some(HEADER) {
+            return Err(error(ProfileErrorKind::BadHeader, 1));
+        }
+
+        let mut profile = Profile::default();
+        for (line, text) in lines.filter(|(_, text)| !text.trim().is_empty()) {
+            let mut fields = text.split_whitespace();
+            let label = fields.next().unwrap_or_default().to_string();
+            let mut count = || match fields.next() {
+                Some(count) => count
+                    .parse()
+                    .map_err(|_| error(ProfileErrorKind::BadCount, line)),
+                None => Err(error(ProfileErrorKind::MissingField, line)),
+            };
+            let block = BlockProfile {
+                entries: count()?,
+                taken: count()?,
+                not_taken: count()?,
+                label,
+            };
+
+            if fields.next().is_some() {
+                return Err(error(ProfileErrorKind::TrailingField, line));
+            }
+            if profile.block(&block.label).is_some() {
+                return Err(error(ProfileErrorKind::DuplicateLabel, line));
+            }
+            profile.blocks.push(block);
+        }
+        Ok(profile)
+    }
+}
+
+/// A report of the blocks that were entered, most entered first
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total: u64 = self.blocks.iter().map(|x| x.entries).sum();
+        writeln!(
+            f,
+            "{:>12} {:>7} {:>12} {:>12}  block",
+            "entries", "share", "taken", "not taken"
+        )?;
+        for block in self.hot_blocks() {
+            let share = 100.0 * block.entries as f64 / total as f64;
+            writeln!(
+                f,
+                "{:>12} {:>6.2}% {:>12} {:>12}  {}",
+                block.entries, share, block.taken, block.not_taken, block.label
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VM {
+    /// Starts counting, from zero, how many times each block of `program` is entered and which
+    /// way its conditional jumps go. The interpreter always counts while the VM is profiling,
+    /// whereas compiled code only counts if compiled with `Options::profile`.
+    pub fn start_profiling(&mut self, program: &Program) {
+        self.counters = vec![0; program.blocks.len() * COUNTERS_PER_BLOCK];
+    }
+
+    /// Whether the VM is counting for a profile
+    pub fn is_profiling(&self) -> bool {
+        !self.counters.is_empty()
+    }
+
+    /// What the VM has counted for `program` since it started profiling, if it has
+    pub fn profile(&self, program: &Program) -> Option<Profile> {
+        if !self.is_profiling() {
+            return None;
+        }
+
+        let counts = self.counters.chunks_exact(COUNTERS_PER_BLOCK);
+        let blocks = program.blocks.iter().zip(counts);
+        let blocks = blocks.map(|(block, counts)| BlockProfile {
+            label: block.label.clone(),
+            entries: counts[ENTRIES],
+            taken: counts[TAKEN],
+            not_taken: counts[NOT_TAKEN],
+        });
+        Some(Profile {
+            blocks: blocks.collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        jit::{Executable, Jit, Options, Target},
+        testing::{self, SAMPLES},
+    };
+
+    fn decode_error(text: &str) -> (ProfileErrorKind, usize) {
+        let err = Profile::decode(text).unwrap_err();
+        (err.kind, err.line)
+    }
+
+    #[test]
+    fn round_trips_profiles() {
+        let block = |label: &str, entries, taken, not_taken| BlockProfile {
+            label: label.to_string(),
+            entries,
+            taken,
+            not_taken,
+        };
+        let profile = Profile {
+            blocks: vec![
+                block("ENTRY", 1, 0, 0),
+                block("LOOP", u64::MAX, 41, 1),
+                block("COLD", 0, 0, 0),
+            ],
+        };
+
+        let text = profile.encode();
+        assert_eq!(
+            text,
+            format!(
+                "{HEADER}\nENTRY 1 0 0\nLOOP {} 41 1\nCOLD 0 0 0\n",
+                u64::MAX
+            )
+        );
+        assert_eq!(Profile::decode(&text), Ok(profile));
+    }
+
+    #[test]
+    fn rejects_files_without_the_header() {
+        assert_eq!(decode_error(""), (ProfileErrorKind::BadHeader, 1));
+        assert_eq!(
+            decode_error("ENTRY 1 0 0\n"),
+            (ProfileErrorKind::BadHeader, 1)
+        );
+    }
+
+    #[test]
+    fn rejects_counts_that_arent_numbers() {
+        let text = format!("{HEADER}\nENTRY 1 0 0\nLOOP 1 -2 0\n");
+        assert_eq!(decode_error(&text), (ProfileErrorKind::BadCount, 3));
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        // Blank lines are skipped, but still counted
+        let text = format!("{HEADER}\n\nENTRY 1 0\n");
+        assert_eq!(decode_error(&text), (ProfileErrorKind::MissingField, 3));
+    }
+
+    #[test]
+    fn rejects_trailing_fields() {
+        let text = format!("{HEADER}\nENTRY 1 0 0 0\n");
+        assert_eq!(decode_error(&text), (ProfileErrorKind::TrailingField, 2));
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() {
+        let text = format!("{HEADER}\nENTRY 1 0 0\nLOOP 2 1 1\nENTRY 1 0 0\n");
+        assert_eq!(decode_error(&text), (ProfileErrorKind::DuplicateLabel, 4));
+    }
+
+    #[test]
+    fn compiled_code_counts_like_the_interpreter() {
+        for (name, code) in SAMPLES {
+            let program = testing::parse(code);
+            let mut interpreted = testing::vm();
+            interpreted.start_profiling(&program);
+            interpreted.run(&program);
+            let expected = interpreted.profile(&program).unwrap();
+            assert!(expected.blocks.iter().any(|x| x.entries > 0));
+
+            for target in [Target::host(), Target::Aarch64] {
+                let options = Options {
+                    profile: true,
+                    ..Options::new(target)
+                };
+                let executable = Executable::new(Jit::compile_with(&program, options).unwrap());
+                let mut compiled = testing::vm();
+                compiled.start_profiling(&program);
+                executable.run(&mut compiled).unwrap();
+
+                let profile = compiled.profile(&program).unwrap();
+                assert_eq!(profile, expected, "{name} on {target:?}");
+            }
+        }
+    }
+}
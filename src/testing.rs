@@ -0,0 +1,54 @@
+//! Programs and VMs shared by the tests of several modules
+
+use crate::{
+    host::{Func, HostRegistry},
+    parser::Parser,
+    verifier,
+    vm::{Program, VM},
+};
+
+/// Every program in `samples/`, by file name
+pub(crate) const SAMPLES: [(&str, &str); 5] = [
+    ("factorial.cj", include_str!("../samples/factorial.cj")),
+    ("gcd.cj", include_str!("../samples/gcd.cj")),
+    ("looper.cj", include_str!("../samples/looper.cj")),
+    ("native.cj", include_str!("../samples/native.cj")),
+    ("stack.cj", include_str!("../samples/stack.cj")),
+];
+
+/// A VM shaped like the one the command line tool runs programs on
+pub(crate) fn vm() -> VM {
+    VM::new(8, 4)
+}
+
+/// Parses and verifies `code`, which can call the host functions the command line tool
+/// registers, except that `print` doesn't print
+pub(crate) fn parse(code: &str) -> Program {
+    extern "C" fn print(x: u64) -> u64 {
+        x
+    }
+    extern "C" fn pow(base: u64, exp: u64) -> u64 {
+        base.wrapping_pow(exp.try_into().unwrap_or(u32::MAX))
+    }
+    extern "C" fn clamp(x: u64, min: u64, max: u64) -> u64 {
+        x.max(min).min(max)
+    }
+
+    let mut host = HostRegistry::default();
+    host.register("print", Func::FnSingleInt64WithReturnInt64(print));
+    host.register("pow", Func::FnDoubleInt64WithReturnInt64(pow));
+    host.register("clamp", Func::FnTripleInt64WithReturnInt64(clamp));
+
+    let program = Parser::new(code).with_host_functions(&host).parse();
+    let program = program.unwrap_or_else(|err| panic!("failed to parse test program: {err}"));
+    verifier::verify(&program, vm().shape())
+        .unwrap_or_else(|err| panic!("failed to verify test program: {err}"));
+    program
+}
+
+/// Panics unless `a` and `b` hold the same registers, locals and operand stack
+pub(crate) fn assert_same_state(a: &VM, b: &VM, context: &str) {
+    assert_eq!(a.registers, b.registers, "registers differ for {context}");
+    assert_eq!(a.locals, b.locals, "locals differ for {context}");
+    assert_eq!(a.stack(), b.stack(), "stacks differ for {context}");
+}
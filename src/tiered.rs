@@ -0,0 +1,193 @@
+//! Runs programs in the interpreter until their blocks get hot, then in compiled code
+
+use crate::{
+    error::{ConfigError, LinkError},
+    interpreter::{ProgramCounter, Status},
+    jit::{Executable, ExitStatus, Jit, Options},
+    profile::Profile,
+    vm::{BlockTarget, Program, VM},
+};
+
+/// How many times a block is entered before it is compiled, unless `JIT_HOT_THRESHOLD` says
+/// otherwise
+pub const DEFAULT_THRESHOLD: u64 = 1000;
+
+/// The threshold set by the `JIT_HOT_THRESHOLD` environment variable, falling back to
+/// `DEFAULT_THRESHOLD` when unset
+pub fn configured_threshold() -> Result<u64, ConfigError> {
+    match std::env::var("JIT_HOT_THRESHOLD") {
+        Ok(threshold) => threshold.trim().parse().map_err(|_| ConfigError {
+            variable: "JIT_HOT_THRESHOLD",
+            value: threshold,
+            expected: "a number",
+        }),
+        Err(_) => Ok(DEFAULT_THRESHOLD),
+    }
+}
+
+/// Runs a program in the interpreter, counting how many times it enters each block. Once a
+/// block has been entered `threshold` times it joins the hot region, which is compiled with
+/// `Jit::compile_region`. From then on, entering a compiled block switches to compiled code,
+/// and compiled code hands back to the interpreter on reaching a block outside the region.
+pub struct Tiered<'a> {
+    program: &'a Program,
+    options: Options,
+    threshold: u64,
+    /// How many times each block has been entered, by its index within the program
+    entries: Vec<u64>,
+    /// The indices of the blocks in the hot region, in the order they got hot
+    hot: Vec<usize>,
+    compiled: Option<Executable>,
+}
+
+impl<'a> Tiered<'a> {
+    pub fn new(program: &'a Program, options: Options, threshold: u64) -> Self {
+        Self {
+            program,
+            options,
+            threshold,
+            entries: vec![0; program.blocks.len()],
+            hot: vec![],
+            compiled: None,
+        }
+    }
+
+    /// The indices of the blocks compiled so far, in the order they got hot
+    pub fn hot_blocks(&self) -> &[usize] {
+        &self.hot
+    }
+
+    /// Compiles the blocks that `profile` counted at least `threshold` entries for up front, so
+    /// that a program profiled by an earlier run doesn't have to warm up again
+    pub fn seed(&mut self, profile: &Profile) -> Result<(), LinkError> {
+        let hot_before = self.hot.len();
+        for (index, block) in self.program.blocks.iter().enumerate() {
+            let entries = profile.block(&block.label).map(|x| x.entries);
+            if entries.is_some_and(|x| x >= self.threshold) && !self.hot.contains(&index) {
+                self.hot.push(index);
+            }
+        }
+        if self.hot.len() == hot_before {
+            return Ok(());
+        }
+
+        eprintln!("profile found {} hot block(s)", self.hot.len() - hot_before);
+        self.compile_hot_region()
+    }
+
+    /// Runs the program on `vm` until it exits, traps or runs out of fuel, switching between the
+    /// interpreter and compiled code at block boundaries. Fails if the hot region can't be
+    /// linked.
+    pub fn run(&mut self, vm: &mut VM) -> Result<Status, LinkError> {
+        loop {
+            if let Some(index) = self.entering_block(vm) {
+                self.entries[index] += 1;
+                if self.entries[index] >= self.threshold && !self.hot.contains(&index) {
+                    self.compile_hot_block(index)?;
+                }
+
+                if let Some(compiled) = self.compiled.as_ref().filter(|x| x.can_resume(vm)) {
+                    match compiled.resume(vm) {
+                        Ok(ExitStatus::Suspended) => continue,
+                        Ok(ExitStatus::Exited(result)) => return Ok(Status::Exited(result)),
+                        Ok(ExitStatus::OutOfFuel) => return Ok(Status::OutOfFuel),
+                        Err(trap) => return Ok(Status::Trapped(trap)),
+                    }
+                }
+            }
+
+            match vm.step(self.program) {
+                Status::Running => continue,
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// The index of the block the VM is about to start, if it is at the start of one. A VM
+    /// that isn't part way through the program is pointed at the start of its entry block.
+    fn entering_block(&self, vm: &mut VM) -> Option<usize> {
+        if self.program.blocks.is_empty() {
+            return None;
+        }
+        let pc = vm.pc.get_or_insert(ProgramCounter {
+            block: BlockTarget(0),
+            instruction: 0,
+        });
+        match pc.instruction {
+            0 => Some(pc.block.0),
+            _ => None,
+        }
+    }
+
+    /// Adds the block at `index` to the hot region, and recompiles the region to include it
+    fn compile_hot_block(&mut self, index: usize) -> Result<(), LinkError> {
+        self.hot.push(index);
+        eprintln!(
+            "block {} is hot, compiling {} block(s)",
+            self.program.blocks[index].label,
+            self.hot.len()
+        );
+        self.compile_hot_region()
+    }
+
+    /// Compiles every block in the hot region, replacing whatever was compiled before
+    fn compile_hot_region(&mut self) -> Result<(), LinkError> {
+        let jit = Jit::compile_region(self.program, self.options, &self.hot)?;
+        self.compiled = Some(Executable::new(jit));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        jit::Target,
+        testing::{self, SAMPLES},
+    };
+
+    #[test]
+    fn tiered_samples_run_like_the_interpreter() {
+        for (name, code) in SAMPLES {
+            let program = testing::parse(code);
+            let mut expected = testing::vm();
+            let expected_status = expected.run(&program);
+
+            for target in [Target::host(), Target::Aarch64] {
+                let mut vm = testing::vm();
+                let mut tiered = Tiered::new(&program, Options::new(target), 2);
+                let status = tiered.run(&mut vm).unwrap();
+
+                let context = format!("{name} on {target:?}");
+                assert_eq!(status, expected_status, "status differs for {context}");
+                testing::assert_same_state(&vm, &expected, &context);
+            }
+        }
+    }
+
+    #[test]
+    fn tiered_runs_resume_once_refuelled() {
+        let (_, code) = SAMPLES[0];
+        let program = testing::parse(code);
+        let mut expected = testing::vm();
+        let expected_status = expected.run(&program);
+
+        for target in [Target::host(), Target::Aarch64] {
+            let options = Options {
+                fuel: true,
+                ..Options::new(target)
+            };
+            let mut vm = testing::vm();
+            vm.set_fuel(Some(20));
+            let mut tiered = Tiered::new(&program, options, 2);
+            assert_eq!(tiered.run(&mut vm).unwrap(), Status::OutOfFuel);
+            assert!(!tiered.hot_blocks().is_empty());
+
+            vm.set_fuel(None);
+            let status = tiered.run(&mut vm).unwrap();
+            let context = format!("resumed factorial on {target:?}");
+            assert_eq!(status, expected_status, "status differs for {context}");
+            testing::assert_same_state(&vm, &expected, &context);
+        }
+    }
+}
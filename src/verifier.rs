@@ -0,0 +1,159 @@
+//! Checks that a program only uses the registers and locals a VM has, and that control never
+//! leaves it, before it is interpreted or compiled. Compiled code indexes registers and locals
+//! without bounds checks, so this must pass before a program is handed to `jit::Jit`.
+
+use crate::{
+    error::{Location, VerifyError, VerifyErrorKind},
+    vm::{BlockTarget, Instruction, Program, Shape, VMLocal, VMRegister},
+};
+
+/// How many registers, and locals per frame, compiled code can address. AArch64 loads and
+/// stores them with a 12-bit immediate index, so any beyond these are rejected even if the VM
+/// has them.
+pub const MAX_ADDRESSABLE: usize = 1 << 12;
+
+/// Returns the first problem found with running `program` on a VM of the given `shape`
+pub fn verify(program: &Program, shape: Shape) -> Result<(), VerifyError> {
+    if program.blocks.is_empty() {
+        return Err(VerifyError {
+            kind: VerifyErrorKind::NoEntryBlock,
+            location: None,
+        });
+    }
+
+    for block in &program.blocks {
+        let location = |instruction| Location {
+            block: block.label.clone(),
+            instruction,
+        };
+
+        for (i, instruction) in block.instructions.iter().enumerate() {
+            verify_instruction(program, shape, instruction).map_err(|kind| VerifyError {
+                kind,
+                location: Some(location(i)),
+            })?;
+        }
+
+        let terminated = block.instructions.last().is_some_and(|x| {
+            matches!(
+                x,
+                Instruction::Exit { .. }
+                    | Instruction::Abort
+                    | Instruction::Jump { .. }
+                    | Instruction::JumpConditional { .. }
+                    | Instruction::Return
+            )
+        });
+        if !terminated {
+            return Err(VerifyError {
+                kind: VerifyErrorKind::Unterminated,
+                location: Some(location(block.instructions.len())),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn verify_instruction(
+    program: &Program,
+    shape: Shape,
+    instruction: &Instruction,
+) -> Result<(), VerifyErrorKind> {
+    // Nearly everything reads or writes the accumulator
+    check_register(shape, VMRegister(0))?;
+
+    match instruction {
+        Instruction::Load { reg } | Instruction::Store { reg } => check_register(shape, *reg),
+        Instruction::Add { rhs }
+        | Instruction::Subtract { rhs }
+        | Instruction::Multiply { rhs }
+        | Instruction::Divide { rhs }
+        | Instruction::Modulo { rhs } => check_register(shape, *rhs),
+        Instruction::Compare { lhs, .. } => check_register(shape, *lhs),
+        Instruction::Exit { result } => result.map_or(Ok(()), |reg| check_register(shape, reg)),
+        Instruction::SetLocal { local } | Instruction::GetLocal { local } => {
+            check_local(shape, *local)
+        }
+        Instruction::CallNative { function } => {
+            (1..=function.func.arity()).try_for_each(|i| check_register(shape, VMRegister(i)))
+        }
+        Instruction::Jump { target } | Instruction::Call { target } => {
+            check_target(program, target)
+        }
+        Instruction::JumpConditional {
+            true_target,
+            false_target,
+        } => check_target(program, true_target).and(check_target(program, false_target)),
+        Instruction::LoadImmediate { .. }
+        | Instruction::Increment
+        | Instruction::Breakpoint
+        | Instruction::Abort
+        | Instruction::Return
+        | Instruction::Push
+        | Instruction::Pop
+        | Instruction::Duplicate
+        | Instruction::Swap => Ok(()),
+    }
+}
+
+fn check_register(shape: Shape, reg: VMRegister) -> Result<(), VerifyErrorKind> {
+    match reg.0 < shape.registers.min(MAX_ADDRESSABLE) {
+        true => Ok(()),
+        false => Err(VerifyErrorKind::RegisterOutOfRange(reg)),
+    }
+}
+
+fn check_local(shape: Shape, local: VMLocal) -> Result<(), VerifyErrorKind> {
+    match local.0 < shape.locals.min(MAX_ADDRESSABLE) {
+        true => Ok(()),
+        false => Err(VerifyErrorKind::LocalOutOfRange(local)),
+    }
+}
+
+fn check_target(program: &Program, target: &BlockTarget) -> Result<(), VerifyErrorKind> {
+    match program.contains(*target) {
+        true => Ok(()),
+        false => Err(VerifyErrorKind::UnknownBlock),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        jit::{Jit, Target},
+        parser::Parser,
+    };
+
+    fn parse(instructions: &[String]) -> Program {
+        let code = format!("ENTRY:\n  {}\n  RET\n", instructions.join("\n  "));
+        Parser::new(&code).parse().unwrap()
+    }
+
+    #[test]
+    fn rejects_slots_compiled_code_cant_address() {
+        let shape = Shape {
+            registers: MAX_ADDRESSABLE + 1,
+            locals: MAX_ADDRESSABLE + 1,
+        };
+        let (last, first_beyond) = (MAX_ADDRESSABLE - 1, MAX_ADDRESSABLE);
+
+        let addressable = parse(&[format!("STORE_REG r{last}"), format!("SET_LOCAL .{last}")]);
+        assert_eq!(verify(&addressable, shape), Ok(()));
+        for target in [Target::Aarch64, Target::X86_64] {
+            Jit::compile_for(&addressable, target).unwrap();
+        }
+
+        let register = parse(&[format!("STORE_REG r{first_beyond}")]);
+        assert_eq!(
+            verify(&register, shape).unwrap_err().kind,
+            VerifyErrorKind::RegisterOutOfRange(VMRegister(first_beyond))
+        );
+
+        let local = parse(&[format!("SET_LOCAL .{first_beyond}")]);
+        assert_eq!(
+            verify(&local, shape).unwrap_err().kind,
+            VerifyErrorKind::LocalOutOfRange(VMLocal(first_beyond))
+        );
+    }
+}
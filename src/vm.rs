@@ -1,21 +1,141 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::{
+    host::HostFunction,
+    interpreter::{Frame, ProgramCounter},
+};
 
-#[derive(Debug, Default)]
+/// The deepest calls can nest, counting the frame of the entry block
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// The most values the operand stack can hold
+pub const MAX_STACK_DEPTH: usize = 256;
+
+/// The fuel of a VM that can run for as long as its program does
+const UNLIMITED_FUEL: u64 = u64::MAX;
+
+#[derive(Debug, Clone)]
 pub struct VM {
     pub registers: Vec<Value>,
+    /// A window of `frame_size` locals for each frame on the call stack, starting with the
+    /// entry block's frame at index 0
     pub locals: Vec<Value>,
+    /// Room for `MAX_STACK_DEPTH` values, of which the bottom `stack_depth` are on the operand
+    /// stack
+    pub(crate) stack: Vec<Value>,
+    pub(crate) stack_depth: usize,
+    pub(crate) frame_size: usize,
+    pub(crate) frames: Vec<Frame>,
+    pub(crate) pc: Option<ProgramCounter>,
+    /// Where and why compiled code trapped, as encoded by `jit::backend::trap`, or zero
+    pub(crate) trap: u64,
+    /// Whether compiled code exited with a result, followed by that result
+    pub(crate) result: [u64; 2],
+    /// How many more instructions the interpreter executes, or blocks metered compiled code
+    /// enters, before running out of fuel, or `UNLIMITED_FUEL`
+    pub(crate) fuel: u64,
+    /// The return address of every call compiled code was nested within when it trapped,
+    /// innermost first, followed by whatever the calls into the entry block left behind
+    pub(crate) return_addresses: [u64; MAX_CALL_DEPTH + 1],
+    /// How many calls the interpreter was nested within when it handed over to compiled code
+    pub(crate) entry_depth: usize,
+    /// The counts of every block of the program being profiled, laid out as `profile` describes,
+    /// or empty if the VM isn't profiling
+    pub(crate) counters: Vec<u64>,
+    /// The address of `counters`, which compiled code reads it through
+    pub(crate) counters_base: usize,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self {
+            registers: vec![],
+            locals: vec![],
+            stack: vec![],
+            stack_depth: 0,
+            frame_size: 0,
+            frames: vec![],
+            pc: None,
+            trap: 0,
+            result: [0; 2],
+            fuel: UNLIMITED_FUEL,
+            return_addresses: [0; MAX_CALL_DEPTH + 1],
+            entry_depth: 0,
+            counters: vec![],
+            counters_base: 0,
+        }
+    }
 }
 
 impl VM {
+    /// Byte offset of `frame_size`, which compiled code reads through its VM pointer
+    pub(crate) const FRAME_SIZE_OFFSET: usize = std::mem::offset_of!(VM, frame_size);
+    /// Byte offset of `stack_depth`, which compiled code loads on entry and stores on exit
+    pub(crate) const STACK_DEPTH_OFFSET: usize = std::mem::offset_of!(VM, stack_depth);
+    /// Byte offset of `trap`, which the trap handler of compiled code stores to
+    pub(crate) const TRAP_OFFSET: usize = std::mem::offset_of!(VM, trap);
+    /// Byte offset of `result`, which compiled code stores to when it exits with a result
+    pub(crate) const RESULT_OFFSET: usize = std::mem::offset_of!(VM, result);
+    /// Byte offset of `fuel`, which metered compiled code takes from at every block it enters
+    pub(crate) const FUEL_OFFSET: usize = std::mem::offset_of!(VM, fuel);
+    /// Byte offset of `return_addresses`, which the trap handler of compiled code fills in
+    pub(crate) const RETURN_ADDRESSES_OFFSET: usize = std::mem::offset_of!(VM, return_addresses);
+    /// Byte offset of `entry_depth`, which compiled code reserves frames for when entered
+    pub(crate) const ENTRY_DEPTH_OFFSET: usize = std::mem::offset_of!(VM, entry_depth);
+    /// Byte offset of `counters_base`, which profiling compiled code counts through
+    pub(crate) const COUNTERS_BASE_OFFSET: usize = std::mem::offset_of!(VM, counters_base);
+
     pub fn new(register_count: usize, local_count: usize) -> Self {
         assert!(register_count > 0);
         Self {
             registers: vec![Value(0); register_count],
-            locals: vec![Value(0); local_count],
+            locals: vec![Value(0); local_count * MAX_CALL_DEPTH],
+            stack: vec![Value(0); MAX_STACK_DEPTH],
+            stack_depth: 0,
+            frame_size: local_count,
+            ..Default::default()
+        }
+    }
+
+    pub fn shape(&self) -> Shape {
+        Shape {
+            registers: self.registers.len(),
+            locals: self.frame_size,
+        }
+    }
+
+    /// The number of calls the interpreter is nested within
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// How much fuel is left, or `None` if the VM can run for as long as its program does
+    pub fn fuel(&self) -> Option<u64> {
+        (self.fuel != UNLIMITED_FUEL).then_some(self.fuel)
+    }
+
+    /// Limits the interpreter to `fuel` more instructions, and metered compiled code to entering
+    /// `fuel` more blocks, before they stop with the VM ready to resume once it is refuelled.
+    /// `None` lifts the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel.unwrap_or(UNLIMITED_FUEL);
+    }
+
+    /// Takes a unit of fuel, unless the VM has run out
+    pub(crate) fn consume_fuel(&mut self) -> bool {
+        match self.fuel {
+            0 => false,
+            UNLIMITED_FUEL => true,
+            _ => {
+                self.fuel -= 1;
+                true
+            }
         }
     }
 
+    /// The values on the operand stack, with the top of the stack last
+    pub fn stack(&self) -> &[Value] {
+        &self.stack[..self.stack_depth]
+    }
+
     pub fn accum_reg(&self) -> &Value {
         &self.registers[0]
     }
@@ -30,94 +150,137 @@ impl VM {
             eprintln!("    [{}] {:?}", i, register);
         }
         eprintln!("Locals:");
-        for (i, local) in self.locals.iter().enumerate() {
+        for (i, local) in self.locals.iter().take(self.frame_size).enumerate() {
             eprintln!("    [{}] {:?}", i, local);
         }
-        eprintln!("");
+        eprintln!("Stack:");
+        for (i, value) in self.stack().iter().enumerate() {
+            eprintln!("    [{}] {:?}", i, value);
+        }
+        eprintln!();
     }
 }
 
-#[derive(Debug, Default)]
+/// How many registers a VM has, and how many locals each frame gets, which programs are
+/// verified against before they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shape {
+    pub registers: usize,
+    pub locals: usize,
+}
+
+/// The basic blocks of a program, starting with its entry block. Blocks refer to each other by
+/// `BlockTarget`, so programs are plain values that can be compared, hashed and shared between
+/// threads.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Program {
-    pub blocks: Vec<Rc<RefCell<BasicBlock>>>,
+    pub blocks: Vec<BasicBlock>,
 }
 
+// Programs are meant to be shared between threads, so nothing in them can be tied to one
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Program>();
+};
+
 impl Program {
-    pub fn make_block(&mut self) -> BlockTarget {
-        let block = BasicBlock::default();
-        let block = Rc::new(RefCell::new(block));
-        self.blocks.push(block.clone());
-        BlockTarget(block, Some(self.blocks.len()))
+    /// Adds an empty block labelled `label` to the end of the program
+    pub fn make_block(&mut self, label: &str) -> BlockTarget {
+        self.blocks.push(BasicBlock {
+            label: label.to_string(),
+            ..Default::default()
+        });
+        BlockTarget(self.blocks.len() - 1)
     }
 
-    pub fn dump(&self) {
-        for (i, block) in self.blocks.iter().enumerate() {
-            eprintln!("Block {}:", i + 1);
-            block.borrow().dump();
-        }
-        eprintln!("");
+    /// The block `target` refers to, which must be part of the program
+    pub fn block(&self, target: BlockTarget) -> &BasicBlock {
+        &self.blocks[target.0]
     }
-}
 
-#[derive(Clone)]
-pub struct BlockTarget(Rc<RefCell<BasicBlock>>, Option<usize>);
-
-impl std::fmt::Debug for BlockTarget {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.1 {
-            Some(id) => f.debug_tuple("BlockTarget").field(&id).finish(),
-            None => f.debug_tuple("BlockTarget").finish(),
-        }
+    pub fn block_mut(&mut self, target: BlockTarget) -> &mut BasicBlock {
+        &mut self.blocks[target.0]
     }
-}
 
-impl BlockTarget {
-    pub fn new(target: Rc<RefCell<BasicBlock>>) -> Self {
-        Self(target, None)
-    }
-    pub fn append(&self, instruction: Instruction) {
-        let instruction = Rc::new(RefCell::new(instruction));
-        self.0.borrow_mut().instructions.push(instruction);
-    }
-    pub fn insert_jump_marker(&self, post_jmp_position: usize) {
-        self.0
-            .borrow_mut()
-            .jumps_to_here
-            .push(post_jmp_position - 4);
-    }
-    pub fn instruction(&self, index: usize) -> Instruction {
-        self.0.borrow().instructions[index].clone().borrow().clone()
+    /// Whether `target` refers to one of the program's blocks
+    pub fn contains(&self, target: BlockTarget) -> bool {
+        target.0 < self.blocks.len()
     }
-    pub fn len(&self) -> usize {
-        self.0.borrow().instructions.len()
+
+    pub fn dump(&self) {
+        for (i, block) in self.blocks.iter().enumerate() {
+            eprintln!("Block {} ({}):", i, block.label);
+            block.dump();
+        }
+        eprintln!();
     }
 }
 
-#[derive(Debug, Default)]
+/// Refers to a block by its index within `Program::blocks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockTarget(pub usize);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct BasicBlock {
-    pub instructions: Vec<Rc<RefCell<Instruction>>>,
-    pub jumps_to_here: Vec<usize>,
-    pub offset: usize,
+    pub label: String,
+    pub instructions: Vec<Instruction>,
 }
 
 impl BasicBlock {
     pub fn dump(&self) {
         for (i, instruction) in self.instructions.iter().enumerate() {
-            eprintln!("    [{}] {:?}", i, instruction.borrow());
+            eprintln!("    [{}] {:?}", i, instruction);
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Value(pub u64);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct VMRegister(pub usize);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct VMLocal(pub usize);
 
-#[derive(Debug, Clone)]
+/// The relation tested by `Instruction::Compare`, always read as `lhs <cond> accumulator`.
+///
+/// Values are compared as unsigned 64-bit integers unless the comparison is one of the
+/// `Signed*` variants, which reinterpret both operands as two's complement `i64`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+    SignedLessThan,
+    SignedLessOrEqual,
+    SignedGreaterThan,
+    SignedGreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn evaluate(self, lhs: u64, rhs: u64) -> bool {
+        let (signed_lhs, signed_rhs) = (lhs as i64, rhs as i64);
+        match self {
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+            Comparison::LessThan => lhs < rhs,
+            Comparison::LessOrEqual => lhs <= rhs,
+            Comparison::GreaterThan => lhs > rhs,
+            Comparison::GreaterOrEqual => lhs >= rhs,
+            Comparison::SignedLessThan => signed_lhs < signed_rhs,
+            Comparison::SignedLessOrEqual => signed_lhs <= signed_rhs,
+            Comparison::SignedGreaterThan => signed_lhs > signed_rhs,
+            Comparison::SignedGreaterOrEqual => signed_lhs >= signed_rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Instruction {
     LoadImmediate {
         value: Value,
@@ -135,14 +298,57 @@ pub enum Instruction {
         local: VMLocal,
     },
     Increment,
-    LessThan {
+    Add {
+        rhs: VMRegister,
+    },
+    Subtract {
+        rhs: VMRegister,
+    },
+    Multiply {
+        rhs: VMRegister,
+    },
+    /// Unsigned division, which traps when dividing by zero
+    Divide {
+        rhs: VMRegister,
+    },
+    /// Unsigned remainder, which traps when dividing by zero
+    Modulo {
+        rhs: VMRegister,
+    },
+    /// Sets the accumulator to 1 when `lhs <cond> accumulator` holds, and to 0 otherwise
+    Compare {
+        cond: Comparison,
         lhs: VMRegister,
     },
     Breakpoint,
-    Exit,
+    /// Returns to the host from any call depth, handing it the value of `result` if there is one
+    Exit {
+        result: Option<VMRegister>,
+    },
+    /// Stops the program with a trap, returning to the host from any call depth
+    Abort,
     Jump {
         target: BlockTarget,
     },
+    /// Continues from `target` in a new frame with its own window of locals
+    Call {
+        target: BlockTarget,
+    },
+    /// Resumes the caller after its `Call`, or returns to the host from the entry frame
+    Return,
+    /// Pushes the accumulator onto the operand stack
+    Push,
+    /// Pops the top of the operand stack into the accumulator
+    Pop,
+    /// Pushes a copy of the top of the operand stack
+    Duplicate,
+    /// Swaps the top two values of the operand stack
+    Swap,
+    /// Calls a host function with registers `r1` onwards as its arguments, and sets the
+    /// accumulator to its result
+    CallNative {
+        function: HostFunction,
+    },
     JumpConditional {
         true_target: BlockTarget,
         false_target: BlockTarget,